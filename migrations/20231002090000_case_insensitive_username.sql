@@ -0,0 +1,26 @@
+-- Add migration script here
+-- usernames keep the casing they were registered with, but "Alice" and
+-- "alice" are the same account
+--
+-- accounts that already differ only in case can't be merged automatically
+-- (each has its own invite code, referrals and logins), so stop here and
+-- name them instead of failing on the index with a bare unique violation
+do $$
+declare
+    duplicates text;
+begin
+    select string_agg(names, '; ') into duplicates
+    from (
+        select string_agg(username, ', ' order by username) as names
+        from users
+        group by lower(username)
+        having count(*) > 1
+    ) as d;
+
+    if duplicates is not null then
+        raise exception 'usernames that differ only in case must be renamed or merged before they can be made case-insensitive: %', duplicates;
+    end if;
+end
+$$;
+
+create unique index users_username_lower_key on users (lower(username));
@@ -0,0 +1,2 @@
+-- Add migration script here
+alter table users add role varchar(32) not null default 'member';
@@ -0,0 +1,2 @@
+-- Add migration script here
+alter table users add deactivated_at timestamptz;
@@ -0,0 +1,9 @@
+-- Add migration script here
+create table login_events (
+    id bigserial primary key,
+    username varchar(255) not null references users (username) on delete cascade,
+    ip_address varchar(45),
+    logged_in_at timestamptz not null default now()
+);
+
+create index login_events_username_logged_in_at_idx on login_events (username, logged_in_at desc);
@@ -0,0 +1,29 @@
+-- Add migration script here
+-- invite codes are matched ignoring case, whatever case policy generated
+-- or received them, so two codes differing only in case can't both exist
+--
+-- codes that already clash would make redeeming either one ambiguous, stop
+-- here and name them rather than failing on the index
+do $$
+declare
+    duplicates text;
+begin
+    select string_agg(codes, '; ') into duplicates
+    from (
+        select string_agg(invite_code, ', ' order by invite_code) as codes
+        from users
+        group by lower(invite_code)
+        having count(*) > 1
+    ) as d;
+
+    if duplicates is not null then
+        raise exception 'invite codes that differ only in case must be regenerated before they can be made case-insensitive: %', duplicates;
+    end if;
+end
+$$;
+
+create unique index users_invite_code_lower_key on users (lower(invite_code));
+
+-- the case-insensitive index already rejects exact duplicates, keeping both
+-- would make a clash surface under either name
+alter table users drop constraint users_invite_code_key;
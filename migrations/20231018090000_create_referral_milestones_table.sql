@@ -0,0 +1,12 @@
+-- Add migration script here
+-- milestones a user has already been celebrated for, so each one fires once
+-- even when their referral count skips past it or two referrals land at once
+create table referral_milestones (
+    username varchar(255) not null references users (username) on delete cascade,
+    milestone bigint not null,
+    reached_at timestamptz not null default now(),
+    primary key (username, milestone)
+);
+
+-- milestones users are already past are recorded at startup from the
+-- configured list, see `backfill_referral_milestones`
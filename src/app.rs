@@ -1,26 +1,63 @@
 use std::{
     net::{IpAddr, SocketAddr},
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use crate::{
-    config::{Config, DatabaseConfig},
-    domain::events::AppEvent,
+    config::{Config, DatabaseConfig, Environment},
+    domain::{
+        errors::ApiError,
+        events::{AppEvent, EventEnvelope, EventLog, Replay},
+        fields::InviteCode,
+    },
+    journal::EventJournal,
+    metrics::{track_latency, Metrics},
+    middleware::{
+        chaos::inject_latency,
+        maintenance::reject_in_maintenance,
+        rate_limit::limit_by_ip,
+        request_id::{propagate_request_id, X_REQUEST_ID},
+        request_log::log_requests,
+        timestamp_format::format_timestamps,
+    },
+    repository::{self, count_users},
     routes::{
-        auth::{authenticate, check_auth},
-        event::stream,
-        health,
-        user::{get_authenticated_user, get_users},
+        admin::{list_admins, list_users, revoke_user_tokens, set_maintenance, validate_config},
+        auth::{auth_status, authenticate, check_auth, logout, refresh_token, require_admin},
+        capabilities,
+        event::{stream, ws},
+        health, metrics, ready,
+        user::{
+            deactivate_authenticated_user, delete_authenticated_user, get_authenticated_user,
+            get_leaderboard, get_my_cohort_rank, get_my_logins, get_my_referral_tree,
+            get_my_referrals, get_share_link, get_users, get_users_batch,
+        },
+    },
+    utils::{
+        idempotency::{IdempotencyStore, InMemoryIdempotencyStore},
+        jwt::JwtKeys,
+        rate_limit::IpWindowCounter,
+        revocation::{InMemoryRevocationStore, RevocationStore},
     },
 };
+use anyhow::Context;
 use axum::{
+    http::{header, HeaderName, HeaderValue, Method},
     middleware,
-    routing::{get, post},
+    routing::{delete, get, post},
     Extension, Router,
 };
-use sqlx::{postgres::PgPoolOptions, Pool, Postgres};
-use tokio::sync::broadcast;
-use tower_http::cors::CorsLayer;
+use prometheus::{IntCounter, Registry};
+use sqlx::{
+    postgres::{PgConnectOptions, PgPoolOptions},
+    Pool, Postgres,
+};
+use tokio::sync::{broadcast, watch};
+use tower_http::cors::{AllowOrigin, CorsLayer};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 #[derive(Clone)]
@@ -32,20 +69,232 @@ impl Db {
     }
 }
 
-#[derive(Clone)]
+/// Counters for problems delivering events to stream subscribers, exported
+/// on `/metrics`. Kept apart from `AppState` so streams don't hold on to the
+/// event sender.
+pub struct StreamStats {
+    serialization_failures: IntCounter,
+    lagged_events: IntCounter,
+}
+
+impl StreamStats {
+    pub fn new() -> prometheus::Result<Self> {
+        Ok(Self {
+            serialization_failures: IntCounter::new(
+                "stream_serialization_failures_total",
+                "Events skipped on SSE and websocket streams because they failed to serialize",
+            )?,
+            lagged_events: IntCounter::new(
+                "stream_lagged_events_total",
+                "Events dropped for SSE and websocket subscribers that fell behind",
+            )?,
+        })
+    }
+
+    pub fn register(&self, registry: &Registry) -> prometheus::Result<()> {
+        registry.register(Box::new(self.serialization_failures.clone()))?;
+        registry.register(Box::new(self.lagged_events.clone()))
+    }
+
+    /// Records an event that could not be serialized for streaming and
+    /// returns the running total.
+    pub fn record_serialization_failure(&self) -> u64 {
+        self.serialization_failures.inc();
+        self.serialization_failures.get()
+    }
+
+    /// Records events dropped for a subscriber that fell behind the channel
+    /// and returns the running total.
+    pub fn record_lagged_events(&self, skipped: u64) -> u64 {
+        self.lagged_events.inc_by(skipped);
+        self.lagged_events.get()
+    }
+
+    pub fn serialization_failures(&self) -> u64 {
+        self.serialization_failures.get()
+    }
+
+    pub fn lagged_events(&self) -> u64 {
+        self.lagged_events.get()
+    }
+}
+
 pub struct AppState {
     db_pool: Db,
-    tx: broadcast::Sender<AppEvent>,
+    read_pool: Option<Db>,
+    tx: broadcast::Sender<EventEnvelope>,
+    event_log: Arc<EventLog>,
+    referral_ip_credits: Option<Arc<IpWindowCounter>>,
+    maintenance_mode: Arc<AtomicBool>,
+    stream_stats: Arc<StreamStats>,
+    revocation_store: Arc<dyn RevocationStore>,
+    shutdown: watch::Receiver<bool>,
+    jwt_keys: Arc<JwtKeys>,
+    metrics: Arc<Metrics>,
+    invite_suffix_length: Mutex<Option<(Instant, u32)>>,
+    idempotency_store: Arc<dyn IdempotencyStore>,
     pub config: Config,
 }
 
 impl AppState {
+    pub fn new(
+        config: Config,
+        db_pool: Db,
+        read_pool: Option<Db>,
+        tx: broadcast::Sender<EventEnvelope>,
+        shutdown: watch::Receiver<bool>,
+    ) -> anyhow::Result<Self> {
+        let referral_ip_credits = config.referral.max_credits_per_ip.map(|limit| {
+            Arc::new(IpWindowCounter::new(
+                limit,
+                Duration::from_secs(config.referral.ip_window_secs),
+            ))
+        });
+        let stream_stats = Arc::new(StreamStats::new()?);
+        let metrics = Arc::new(Metrics::new(&stream_stats)?);
+
+        Ok(Self {
+            db_pool,
+            read_pool,
+            tx,
+            event_log: Arc::new(EventLog::new(config.application.event_history_size)),
+            referral_ip_credits,
+            maintenance_mode: Arc::new(AtomicBool::new(config.application.maintenance_mode)),
+            stream_stats,
+            revocation_store: Arc::new(InMemoryRevocationStore::default()),
+            shutdown,
+            jwt_keys: Arc::new(JwtKeys::from_config(&config.jwt)?),
+            metrics,
+            invite_suffix_length: Mutex::new(None),
+            idempotency_store: Arc::new(InMemoryIdempotencyStore::default()),
+            config,
+        })
+    }
+
     pub fn get_pool(&self) -> Pool<Postgres> {
         self.db_pool.inner()
     }
 
-    pub fn get_sender(&self) -> broadcast::Sender<AppEvent> {
-        self.tx.clone()
+    /// Pool for read-only listings: the replica when one is configured,
+    /// otherwise the primary. Anything that must see a write made in the
+    /// same request should use `get_pool`.
+    pub fn get_read_pool(&self) -> Pool<Postgres> {
+        self.read_pool.as_ref().unwrap_or(&self.db_pool).inner()
+    }
+
+    pub fn publish(&self, event: AppEvent) {
+        self.event_log.publish(&self.tx, event);
+    }
+
+    pub fn subscribe(
+        &self,
+        last_event_id: Option<u64>,
+    ) -> (broadcast::Receiver<EventEnvelope>, Replay) {
+        self.event_log.subscribe(&self.tx, last_event_id)
+    }
+
+    pub fn get_stream_stats(&self) -> Arc<StreamStats> {
+        self.stream_stats.clone()
+    }
+
+    /// Flips to `true` once the server starts shutting down, so long-lived
+    /// responses like SSE streams can end instead of holding shutdown up.
+    pub fn get_shutdown(&self) -> watch::Receiver<bool> {
+        self.shutdown.clone()
+    }
+
+    pub fn get_metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    pub fn get_jwt_keys(&self) -> Arc<JwtKeys> {
+        self.jwt_keys.clone()
+    }
+
+    pub fn get_idempotency_store(&self) -> Arc<dyn IdempotencyStore> {
+        self.idempotency_store.clone()
+    }
+
+    pub fn get_revocation_store(&self) -> Arc<dyn RevocationStore> {
+        self.revocation_store.clone()
+    }
+
+    /// Rejects every access and refresh token issued to `username` so far,
+    /// for as long as the longest-lived of them could still be valid.
+    pub fn revoke_all_tokens(&self, username: &str) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let jwt = &self.config.jwt;
+        self.revocation_store
+            .revoke_all(username, now, now + jwt.exp.max(jwt.refresh_exp));
+    }
+
+    /// Fails with a 503 while maintenance mode is on. Everything that writes
+    /// user data goes through this, mostly via `reject_in_maintenance`.
+    pub fn ensure_writable(&self) -> Result<(), ApiError> {
+        if self.in_maintenance() {
+            return Err(ApiError::MaintenanceMode(
+                self.config.application.maintenance_retry_after_secs,
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn in_maintenance(&self) -> bool {
+        self.maintenance_mode.load(Ordering::Relaxed)
+    }
+
+    pub fn set_maintenance(&self, enabled: bool) {
+        self.maintenance_mode.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Suffix length for newly generated invite codes. With scaling on it
+    /// grows with the user count, which is only recounted once the cached
+    /// count is older than `user_count_cache_secs`.
+    pub async fn invite_suffix_length(&self) -> u32 {
+        let config = &self.config.invite_code;
+        if !config.scale_with_user_count {
+            return config.suffix_length;
+        }
+
+        let cached = *self.invite_suffix_length.lock().unwrap();
+        if let Some((counted_at, length)) = cached {
+            if counted_at.elapsed() < Duration::from_secs(config.user_count_cache_secs) {
+                return length;
+            }
+        }
+
+        // generated codes must still pass `InviteCode::parse` when redeemed
+        let max_length =
+            InviteCode::MAX_SUFFIX_LENGTH.min(config.max_length.saturating_sub(3) as u32);
+        match count_users(&self.get_pool()).await {
+            Ok(count) => {
+                let length = InviteCode::suffix_length_for(count, config.suffix_length, max_length);
+                *self.invite_suffix_length.lock().unwrap() = Some((Instant::now(), length));
+                length
+            }
+            // keep using the last known length rather than failing signups
+            Err(_) => cached.map_or(config.suffix_length, |(_, length)| length),
+        }
+    }
+
+    /// Returns false when `ip` has already been credited the configured
+    /// maximum number of referrals within the window.
+    pub fn try_credit_referral(&self, ip: IpAddr) -> bool {
+        match &self.referral_ip_credits {
+            Some(credits) => credits.try_acquire(ip).is_ok(),
+            None => true,
+        }
+    }
+
+    /// Hands back a credit from `try_credit_referral` when the registration it
+    /// was taken for failed.
+    pub fn refund_referral_credit(&self, ip: IpAddr) {
+        if let Some(credits) = &self.referral_ip_credits {
+            credits.release(ip);
+        }
     }
 }
 
@@ -54,39 +303,179 @@ pub struct Application;
 impl Application {
     pub async fn build(config: Config) -> anyhow::Result<()> {
         Self::setup_tracing(&config.application.log_level);
+        config.validate()?;
+        repository::set_retry_on_connection_error(config.database.retry_on_connection_error);
 
-        let db_pool = Self::get_pool(&config.database).await;
-        let (tx, _rx) = broadcast::channel(100);
-        let app_state = Arc::new(AppState {
-            db_pool: db_pool.clone(),
+        let db_pool = Self::get_pool(&config.database, config.database.get_connect_options());
+        let read_pool = match config.database.get_read_connect_options()? {
+            Some(options) => {
+                tracing::info!("routing reads to the replica");
+                Some(Self::get_pool(&config.database, options))
+            }
+            None => None,
+        };
+        if config.database.verify_db_on_startup {
+            Self::verify_pool(&db_pool)
+                .await
+                .context("verifying the database connection")?;
+            if let Some(read_pool) = &read_pool {
+                Self::verify_pool(read_pool)
+                    .await
+                    .context("verifying the read replica connection")?;
+            }
+            tracing::info!("database connection verified");
+        }
+        // the error is logged, at worst users past a new milestone get it on
+        // their next referral
+        if let Ok(backfilled) =
+            repository::backfill_referral_milestones(&db_pool.inner(), &config.referral.milestones)
+                .await
+        {
+            tracing::info!("referral milestones already reached >>> {}", backfilled);
+        }
+        let (tx, _rx) = broadcast::channel(config.application.event_buffer_size);
+        let journal = match &config.application.event_journal_path {
+            Some(path) => {
+                let journal = EventJournal::open(
+                    path.clone(),
+                    config.application.event_journal_max_bytes,
+                    config.application.timestamp_format,
+                )
+                .await?;
+                tracing::info!("journaling events to >>> {}", path.display());
+                Some(journal.spawn(tx.subscribe()))
+            }
+            None => None,
+        };
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let app_state = Arc::new(AppState::new(
+            config.clone(),
+            db_pool,
+            read_pool,
             tx,
-            config: config.clone(),
-        });
+            shutdown_rx,
+        )?);
+        let mut app = Self::router(app_state)?;
 
-        let cors = CorsLayer::permissive();
-        let app = Router::new()
-            .route("/stream", get(stream))
-            .route("/users/me", get(get_authenticated_user))
-            .route("/users", get(get_users))
-            .route_layer(middleware::from_fn(check_auth))
-            .route("/health", get(health))
-            .route("/authenticate", post(authenticate))
-            .with_state(app_state)
-            .layer(Extension(db_pool.clone()))
-            .layer(Extension(config.clone()))
-            .layer(cors);
+        if let Some(latency) = config.chaos_latency() {
+            tracing::warn!(
+                "chaos mode: injecting {:?} latency into every response",
+                latency
+            );
+            app = app.layer(middleware::from_fn_with_state(latency, inject_latency));
+        } else if config.chaos.latency_ms > 0 && config.environment == Environment::Production {
+            tracing::warn!("chaos latency is configured but refused in production");
+        }
 
         let ip = config.application.host.parse::<IpAddr>()?;
         let addr = SocketAddr::new(ip, config.application.port);
         tracing::info!("listening on {}", addr.port());
         axum::Server::bind(&addr)
-            .serve(app.into_make_service())
-            .await
-            .unwrap();
+            .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+            .with_graceful_shutdown(async move {
+                shutdown_signal().await;
+                tracing::info!("shutdown signal received, draining connections");
+                let _ = shutdown_tx.send(true);
+            })
+            .await?;
+
+        tracing::info!("server stopped");
+        // the server owned the last sender, so the journal sees the channel
+        // close and flushes
+        if let Some(journal) = journal {
+            journal.await?;
+        }
 
         Ok(())
     }
 
+    fn router(app_state: Arc<AppState>) -> anyhow::Result<Router> {
+        let config = app_state.config.clone();
+        let authenticate_limiter = config.rate_limit.authenticate_max_requests.map(|limit| {
+            Arc::new(IpWindowCounter::new(
+                limit,
+                Duration::from_secs(config.rate_limit.authenticate_window_secs),
+            ))
+        });
+
+        let cors = Self::cors_layer(&config.application.allowed_origins)?;
+        let admin = Router::new()
+            .route("/admin/users", get(list_users))
+            .route("/admin/admins", get(list_admins))
+            .route(
+                "/admin/users/:username/revoke-tokens",
+                post(revoke_user_tokens),
+            )
+            .route("/admin/maintenance", post(set_maintenance))
+            .route("/admin/config/validate", get(validate_config))
+            .route_layer(middleware::from_fn(require_admin));
+
+        let mut authed = Router::new().merge(admin).route("/stream", get(stream));
+        if config.application.websocket_enabled {
+            authed = authed.route("/ws", get(ws));
+        }
+
+        let mut public = Router::new()
+            .route("/health", get(health))
+            .route("/ready", get(ready))
+            .route("/capabilities", get(capabilities))
+            .route("/metrics", get(metrics))
+            .route(
+                "/authenticate",
+                match authenticate_limiter {
+                    Some(limiter) => post(authenticate)
+                        .route_layer(middleware::from_fn_with_state(limiter, limit_by_ip)),
+                    None => post(authenticate),
+                },
+            );
+        if config.jwt.refresh_tokens {
+            public = public.route("/token/refresh", post(refresh_token));
+        }
+
+        let writes = middleware::from_fn_with_state(app_state.clone(), reject_in_maintenance);
+        Ok(authed
+            .route(
+                "/users/me",
+                get(get_authenticated_user)
+                    .merge(delete(delete_authenticated_user).route_layer(writes.clone())),
+            )
+            .route(
+                "/users/me/deactivate",
+                post(deactivate_authenticated_user).route_layer(writes),
+            )
+            .route("/users/me/share-link", get(get_share_link))
+            .route("/users/me/cohort-rank", get(get_my_cohort_rank))
+            .route("/users/me/referrals", get(get_my_referrals))
+            .route("/users/me/tree", get(get_my_referral_tree))
+            .route("/users/me/logins", get(get_my_logins))
+            .route("/users", get(get_users))
+            .route("/users/batch", post(get_users_batch))
+            .route("/leaderboard", get(get_leaderboard))
+            .route("/auth/status", get(auth_status))
+            .route("/logout", post(logout))
+            .route_layer(middleware::from_fn(check_auth))
+            .merge(public)
+            .layer(middleware::from_fn_with_state(
+                config.application.timestamp_format,
+                format_timestamps,
+            ))
+            .layer(middleware::from_fn_with_state(
+                app_state.get_metrics(),
+                track_latency,
+            ))
+            .layer(middleware::from_fn_with_state(
+                Arc::new(config.logging.clone()),
+                log_requests,
+            ))
+            .layer(Extension(app_state.db_pool.clone()))
+            .layer(Extension(config))
+            .layer(Extension(app_state.get_revocation_store()))
+            .layer(Extension(app_state.get_jwt_keys()))
+            .with_state(app_state)
+            .layer(cors)
+            .layer(middleware::from_fn(propagate_request_id)))
+    }
+
     fn setup_tracing(log_level: &str) {
         tracing_subscriber::registry()
             .with(
@@ -97,10 +486,264 @@ impl Application {
             .init();
     }
 
-    async fn get_pool(db_config: &DatabaseConfig) -> Db {
+    /// Restricts CORS to the configured origins. No origins (or `*`) keeps the
+    /// permissive behaviour for local development.
+    fn cors_layer(allowed_origins: &[String]) -> anyhow::Result<CorsLayer> {
+        if allowed_origins.is_empty() || allowed_origins.iter().any(|o| o == "*") {
+            return Ok(CorsLayer::permissive());
+        }
+
+        let origins = allowed_origins
+            .iter()
+            .map(|o| o.parse::<HeaderValue>())
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(CorsLayer::new()
+            .allow_origin(AllowOrigin::list(origins))
+            .allow_methods([Method::GET, Method::POST, Method::DELETE])
+            .allow_headers([
+                header::AUTHORIZATION,
+                header::CONTENT_TYPE,
+                HeaderName::from_static("last-event-id"),
+                HeaderName::from_static("idempotency-key"),
+                X_REQUEST_ID.clone(),
+            ])
+            .expose_headers([X_REQUEST_ID.clone()]))
+    }
+
+    fn get_pool(db_config: &DatabaseConfig, options: PgConnectOptions) -> Db {
         let pool = PgPoolOptions::new()
+            .max_connections(db_config.max_connections)
+            .min_connections(db_config.min_connections)
             .acquire_timeout(std::time::Duration::from_secs(2))
-            .connect_lazy_with(db_config.get_connect_options());
+            .connect_lazy_with(options);
         Db(pool)
     }
+
+    async fn verify_pool(pool: &Db) -> anyhow::Result<()> {
+        sqlx::query("select 1").execute(&pool.inner()).await?;
+        Ok(())
+    }
+}
+
+/// Completes on ctrl-c, or on SIGTERM where there are unix signals.
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!("listening for ctrl-c failed >>> {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                tracing::error!("listening for SIGTERM failed >>> {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+/// The whole app over `pool`, for tests that go through the real routes and
+/// middleware. Every request comes from 127.0.0.1.
+#[cfg(test)]
+pub(crate) struct TestApp {
+    router: Router,
+    pub state: Arc<AppState>,
+    // streams end as soon as the shutdown sender goes away
+    _shutdown: watch::Sender<bool>,
+}
+
+#[cfg(test)]
+impl TestApp {
+    pub fn new(config: Config, pool: Pool<Postgres>) -> Self {
+        Self::with_read_pool(config, pool, None)
+    }
+
+    /// Like `new`, with list queries going to `read_pool` as they would to a
+    /// replica.
+    pub fn with_read_pool(
+        config: Config,
+        pool: Pool<Postgres>,
+        read_pool: Option<Pool<Postgres>>,
+    ) -> Self {
+        use axum::extract::ConnectInfo;
+
+        let (tx, _rx) = broadcast::channel(config.application.event_buffer_size);
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let state =
+            Arc::new(AppState::new(config, Db(pool), read_pool.map(Db), tx, shutdown_rx).unwrap());
+        let router = Application::router(state.clone())
+            .unwrap()
+            .layer(Extension(ConnectInfo(SocketAddr::from((
+                [127, 0, 0, 1],
+                4000,
+            )))));
+
+        Self {
+            router,
+            state,
+            _shutdown: shutdown_tx,
+        }
+    }
+
+    pub async fn send(
+        &self,
+        request: axum::http::Request<axum::body::Body>,
+    ) -> axum::response::Response {
+        use tower::ServiceExt;
+
+        self.router.clone().oneshot(request).await.unwrap()
+    }
+
+    /// Serves the app on a free local port, for clients that need a real
+    /// connection such as websockets. Stops with the test's runtime.
+    pub fn serve(&self) -> SocketAddr {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(self.router.clone().into_make_service());
+        tokio::spawn(server);
+        addr
+    }
+
+    /// Sends `body` as JSON, authenticated with `token` when there is one,
+    /// and returns the status with the JSON response (`Null` when empty).
+    pub async fn call(
+        &self,
+        method: Method,
+        uri: &str,
+        token: Option<&str>,
+        body: Option<serde_json::Value>,
+    ) -> (axum::http::StatusCode, serde_json::Value) {
+        let mut request = axum::http::Request::builder().method(method).uri(uri);
+        if let Some(token) = token {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+        let request = match body {
+            Some(body) => request
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(axum::body::Body::from(body.to_string())),
+            None => request.body(axum::body::Body::empty()),
+        };
+
+        let response = self.send(request.unwrap()).await;
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body = serde_json::from_slice(&body).unwrap_or(serde_json::Value::Null);
+        (status, body)
+    }
+
+    /// Registers `username` (or logs them in) and returns the tokens.
+    pub async fn authenticate(
+        &self,
+        username: &str,
+        invitation_code: Option<&str>,
+    ) -> serde_json::Value {
+        let (status, body) = self
+            .call(
+                Method::POST,
+                "/authenticate",
+                None,
+                Some(serde_json::json!({
+                    "username": username,
+                    "invitationCode": invitation_code,
+                })),
+            )
+            .await;
+        assert!(
+            status.is_success(),
+            "authenticating {} >>> {}",
+            username,
+            body
+        );
+        body
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, http::Request};
+    use tower::ServiceExt;
+
+    fn from_origin(origin: &str) -> Request<Body> {
+        Request::builder()
+            .uri("/")
+            .header(header::ORIGIN, origin)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn cors_only_allows_the_configured_origins() {
+        let cors = Application::cors_layer(&["https://app.example".to_owned()]).unwrap();
+        let app = Router::new().route("/", get(|| async { "ok" })).layer(cors);
+
+        let response = app
+            .clone()
+            .oneshot(from_origin("https://app.example"))
+            .await
+            .unwrap();
+        assert_eq!(
+            response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://app.example"
+        );
+
+        let response = app
+            .oneshot(from_origin("https://evil.example"))
+            .await
+            .unwrap();
+        assert!(!response
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+    }
+
+    #[tokio::test]
+    async fn cors_without_origins_allows_any() {
+        let cors = Application::cors_layer(&[]).unwrap();
+        let app = Router::new().route("/", get(|| async { "ok" })).layer(cors);
+
+        let response = app
+            .oneshot(from_origin("https://anywhere.example"))
+            .await
+            .unwrap();
+        assert_eq!(response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+    }
+
+    #[tokio::test]
+    async fn verifying_an_unreachable_database_fails_fast() {
+        let mut config = crate::config::test_config();
+        // nothing listens on port 1
+        config.database.port = 1;
+        let pool = Application::get_pool(&config.database, config.database.get_connect_options());
+
+        let verified =
+            tokio::time::timeout(Duration::from_secs(5), Application::verify_pool(&pool))
+                .await
+                .expect("verifying should give up well before 5s");
+        assert!(verified.is_err());
+    }
+
+    #[test]
+    fn an_invalid_replica_url_is_refused_before_connecting() {
+        let mut config = crate::config::test_config();
+        config.database.read_database_url = Some("not a connection string".to_owned().into());
+        let error = config.database.get_read_connect_options().unwrap_err();
+        assert!(error.to_string().contains("database.read_database_url"));
+    }
 }
@@ -1,11 +1,26 @@
+use crate::domain::{
+    fields::{CasePolicy, InviteCode, User, Username},
+    timestamp::TimestampFormat,
+};
+use jsonwebtoken::Algorithm;
 use secrecy::{ExposeSecret, Secret};
 use serde_aux::field_attributes::deserialize_number_from_string;
 use sqlx::{
     postgres::{PgConnectOptions, PgSslMode},
     ConnectOptions,
 };
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    path::PathBuf,
+    time::Duration,
+};
+use time::OffsetDateTime;
+use url::{Host, Url};
 
-enum Environment {
+#[derive(serde::Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(try_from = "String")]
+pub enum Environment {
+    #[default]
     Local,
     Production,
 }
@@ -40,6 +55,73 @@ pub struct ApplicationConfig {
     #[serde(deserialize_with = "deserialize_number_from_string")]
     pub port: u16,
     pub log_level: String,
+    pub public_base_url: Url,
+    #[serde(default)]
+    pub debug_mode: bool,
+    #[serde(default)]
+    pub trust_forwarded_for: bool,
+    /// Proxies in front of the app that append to `X-Forwarded-For`. The
+    /// client is taken this many entries from the right.
+    #[serde(default = "default_trusted_proxy_hops")]
+    pub trusted_proxy_hops: usize,
+    #[serde(default)]
+    pub maintenance_mode: bool,
+    #[serde(default = "default_maintenance_retry_after_secs")]
+    pub maintenance_retry_after_secs: u64,
+    #[serde(default = "default_event_buffer_size")]
+    pub event_buffer_size: usize,
+    #[serde(default = "default_event_history_size")]
+    pub event_history_size: usize,
+    #[serde(default)]
+    pub timestamp_format: TimestampFormat,
+    #[serde(default)]
+    pub allowed_origins: Vec<String>,
+    #[serde(default)]
+    pub event_journal_path: Option<PathBuf>,
+    #[serde(default = "default_event_journal_max_bytes")]
+    pub event_journal_max_bytes: u64,
+    /// How long `/authenticate` responses are kept for `Idempotency-Key`
+    /// retries.
+    #[serde(default = "default_idempotency_ttl_secs")]
+    pub idempotency_ttl_secs: u64,
+    /// Serves the event stream over a websocket at `/ws` as well as SSE.
+    #[serde(default = "default_websocket_enabled")]
+    pub websocket_enabled: bool,
+    /// Largest `limit` a paginated listing accepts, bigger values are clamped.
+    #[serde(default = "default_max_page_size")]
+    pub max_page_size: i64,
+}
+
+fn default_event_journal_max_bytes() -> u64 {
+    10 * 1024 * 1024
+}
+
+fn default_idempotency_ttl_secs() -> u64 {
+    300
+}
+
+fn default_trusted_proxy_hops() -> usize {
+    1
+}
+
+fn default_websocket_enabled() -> bool {
+    true
+}
+
+fn default_max_page_size() -> i64 {
+    100
+}
+
+fn default_event_buffer_size() -> usize {
+    100
+}
+
+fn default_event_history_size() -> usize {
+    100
+}
+
+fn default_maintenance_retry_after_secs() -> u64 {
+    300
 }
 
 #[derive(serde::Deserialize, Clone)]
@@ -51,6 +133,33 @@ pub struct DatabaseConfig {
     pub host: String,
     pub database_name: String,
     pub require_ssl: bool,
+    #[serde(
+        default = "default_max_connections",
+        deserialize_with = "deserialize_number_from_string"
+    )]
+    pub max_connections: u32,
+    #[serde(default, deserialize_with = "deserialize_number_from_string")]
+    pub min_connections: u32,
+    /// Retry a query once when it fails on a broken connection, e.g. after
+    /// Postgres restarts underneath the pool.
+    #[serde(default = "default_retry_on_connection_error")]
+    pub retry_on_connection_error: bool,
+    /// Optional replica for read-heavy listings. Without it reads go to the
+    /// primary.
+    #[serde(default)]
+    pub read_database_url: Option<Secret<String>>,
+    /// Connect and run `select 1` during startup so a bad host or bad
+    /// credentials stop the process instead of failing the first request.
+    #[serde(default)]
+    pub verify_db_on_startup: bool,
+}
+
+fn default_max_connections() -> u32 {
+    10
+}
+
+fn default_retry_on_connection_error() -> bool {
+    true
 }
 
 impl DatabaseConfig {
@@ -60,6 +169,19 @@ impl DatabaseConfig {
         options
     }
 
+    pub fn get_read_connect_options(&self) -> anyhow::Result<Option<PgConnectOptions>> {
+        let Some(url) = &self.read_database_url else {
+            return Ok(None);
+        };
+
+        let mut options = url
+            .expose_secret()
+            .parse::<PgConnectOptions>()
+            .map_err(|e| anyhow::anyhow!("invalid database.read_database_url >>> {}", e))?;
+        options.log_statements(tracing_log::log::LevelFilter::Trace);
+        Ok(Some(options))
+    }
+
     pub fn without_db(&self) -> PgConnectOptions {
         let ssl_mode = if self.require_ssl {
             PgSslMode::Require
@@ -82,13 +204,544 @@ pub struct JwtConfig {
     pub iss: String,
     #[serde(deserialize_with = "deserialize_number_from_string")]
     pub exp: u64,
+    pub refresh_secret: Secret<String>,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub refresh_exp: u64,
+    /// Issue refresh tokens on authenticate and serve `/token/refresh`.
+    #[serde(default = "default_refresh_tokens")]
+    pub refresh_tokens: bool,
+    #[serde(default = "default_strict_bearer")]
+    pub strict_bearer: bool,
+    #[serde(default)]
+    pub secret_entropy_check: EntropyCheck,
+    /// When off, expired tokens are reported as `TOKEN_INVALID` like any
+    /// other bad token.
+    #[serde(default = "default_report_expired_tokens")]
+    pub report_expired_tokens: bool,
+    /// Algorithm for access tokens. HMAC algorithms sign with `secret`, the
+    /// others need both PEM key paths.
+    #[serde(default)]
+    pub algorithm: Algorithm,
+    #[serde(default)]
+    pub private_key_path: Option<PathBuf>,
+    #[serde(default)]
+    pub public_key_path: Option<PathBuf>,
+    /// Clock skew allowed when checking `exp`.
+    #[serde(
+        default = "default_leeway_secs",
+        deserialize_with = "deserialize_number_from_string"
+    )]
+    pub leeway_secs: u64,
+}
+
+fn default_leeway_secs() -> u64 {
+    60
+}
+
+fn default_refresh_tokens() -> bool {
+    true
+}
+
+fn default_report_expired_tokens() -> bool {
+    true
+}
+
+fn default_strict_bearer() -> bool {
+    true
+}
+
+#[derive(serde::Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum EntropyCheck {
+    Off,
+    #[default]
+    Warn,
+    Fail,
+}
+
+impl JwtConfig {
+    const MIN_DISTINCT_CHARS: usize = 10;
+
+    /// Rough guess at whether a secret is guessable: too few distinct
+    /// characters, or a long secret made of a small repeated alphabet.
+    fn is_low_entropy(secret: &str) -> bool {
+        let length = secret.chars().count();
+        let distinct = secret.chars().collect::<HashSet<_>>().len();
+        distinct < Self::MIN_DISTINCT_CHARS || (distinct as f64) / (length as f64) < 0.1
+    }
+
+    fn algorithm_issues(&self, issues: &mut Vec<ConfigIssue>) {
+        let is_hmac = matches!(
+            self.algorithm,
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512
+        );
+        let has_keys = self.private_key_path.is_some() && self.public_key_path.is_some();
+
+        if is_hmac && (self.private_key_path.is_some() || self.public_key_path.is_some()) {
+            issues.push(ConfigIssue::error(format!(
+                "jwt.algorithm {:?} signs with jwt.secret, key paths must not be set",
+                self.algorithm
+            )));
+        }
+
+        if !is_hmac && !has_keys {
+            issues.push(ConfigIssue::error(format!(
+                "jwt.algorithm {:?} needs jwt.private_key_path and jwt.public_key_path",
+                self.algorithm
+            )));
+        }
+    }
+
+    fn secret_entropy_issues(&self, issues: &mut Vec<ConfigIssue>) {
+        let secrets = [
+            ("jwt.secret", &self.secret),
+            ("jwt.refresh_secret", &self.refresh_secret),
+        ];
+
+        for (name, secret) in secrets {
+            if !Self::is_low_entropy(secret.expose_secret()) {
+                continue;
+            }
+
+            let message = format!("{} looks like a low-entropy secret", name);
+            match self.secret_entropy_check {
+                EntropyCheck::Off => {}
+                EntropyCheck::Warn => issues.push(ConfigIssue::warning(message)),
+                EntropyCheck::Fail => issues.push(ConfigIssue::error(message)),
+            }
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct UsernameConfig {
+    #[serde(default = "default_username_min_length")]
+    pub min_length: usize,
+    #[serde(default = "default_username_max_length")]
+    pub max_length: usize,
+}
+
+fn default_username_min_length() -> usize {
+    Username::DEFAULT_MIN_LENGTH
+}
+
+fn default_username_max_length() -> usize {
+    Username::DEFAULT_MAX_LENGTH
+}
+
+impl Default for UsernameConfig {
+    fn default() -> Self {
+        Self {
+            min_length: default_username_min_length(),
+            max_length: default_username_max_length(),
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct InviteCodeConfig {
+    #[serde(default = "default_invite_code_max_length")]
+    pub max_length: usize,
+    #[serde(default)]
+    pub case: CasePolicy,
+    #[serde(default = "default_invite_code_suffix_length")]
+    pub suffix_length: u32,
+    #[serde(default = "default_invite_code_max_attempts")]
+    pub max_generation_attempts: u32,
+    /// Lengthen the suffix past `suffix_length` as the user count grows.
+    #[serde(default)]
+    pub scale_with_user_count: bool,
+    /// How long a user count is reused before counting again.
+    #[serde(
+        default = "default_user_count_cache_secs",
+        deserialize_with = "deserialize_number_from_string"
+    )]
+    pub user_count_cache_secs: u64,
+}
+
+fn default_invite_code_suffix_length() -> u32 {
+    6
+}
+
+fn default_invite_code_max_attempts() -> u32 {
+    10
+}
+
+fn default_user_count_cache_secs() -> u64 {
+    300
+}
+
+fn default_invite_code_max_length() -> usize {
+    InviteCode::DEFAULT_MAX_LENGTH
+}
+
+impl Default for InviteCodeConfig {
+    fn default() -> Self {
+        Self {
+            max_length: default_invite_code_max_length(),
+            case: CasePolicy::default(),
+            suffix_length: default_invite_code_suffix_length(),
+            max_generation_attempts: default_invite_code_max_attempts(),
+            scale_with_user_count: false,
+            user_count_cache_secs: default_user_count_cache_secs(),
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct ReferralConfig {
+    #[serde(default)]
+    pub max_credits_per_ip: Option<usize>,
+    #[serde(default = "default_ip_window_secs")]
+    pub ip_window_secs: u64,
+    #[serde(default)]
+    pub attribution_window_secs: Option<u64>,
+    /// Referral counts that earn the referrer a `ReferralMilestone` event.
+    #[serde(default = "default_milestones")]
+    pub milestones: Vec<i64>,
+    /// Deepest level `/users/me/tree` will walk.
+    #[serde(default = "default_max_tree_depth")]
+    pub max_tree_depth: i32,
+    /// Most referrals `/users/me/tree` lists under a single user.
+    #[serde(default = "default_max_tree_children")]
+    pub max_tree_children: usize,
+    #[serde(default)]
+    pub deactivated_referrer: DeactivatedReferrer,
+}
+
+/// How a deactivated account is shown where it's someone's referrer: in
+/// `expand=referrer` and as an inner node of `/users/me/tree`.
+#[derive(serde::Deserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum DeactivatedReferrer {
+    /// As `{ username, deleted: true }`, keeping the rest of the tree below it.
+    #[default]
+    Flag,
+    /// As `null`, and left out of the tree along with its downline.
+    Hide,
+}
+
+fn default_ip_window_secs() -> u64 {
+    86400
+}
+
+fn default_milestones() -> Vec<i64> {
+    vec![1, 5, 10, 25]
+}
+
+fn default_max_tree_depth() -> i32 {
+    5
+}
+
+fn default_max_tree_children() -> usize {
+    50
+}
+
+impl Default for ReferralConfig {
+    fn default() -> Self {
+        Self {
+            max_credits_per_ip: None,
+            ip_window_secs: default_ip_window_secs(),
+            attribution_window_secs: None,
+            milestones: default_milestones(),
+            max_tree_depth: default_max_tree_depth(),
+            max_tree_children: default_max_tree_children(),
+            deactivated_referrer: DeactivatedReferrer::default(),
+        }
+    }
+}
+
+impl ReferralConfig {
+    /// Whether a referral through `referrer`'s invite code still earns them
+    /// credit. Codes are created when their owner joins, so the window runs
+    /// from the referrer's join date.
+    pub fn within_attribution_window(&self, referrer: &User) -> bool {
+        match self.attribution_window_secs {
+            Some(window) => {
+                let age = OffsetDateTime::now_utc() - referrer.joined_at;
+                age.whole_seconds() <= window as i64
+            }
+            None => true,
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct RateLimitConfig {
+    /// Requests a single client IP may make to `/authenticate` per window.
+    #[serde(default)]
+    pub authenticate_max_requests: Option<usize>,
+    #[serde(default = "default_rate_limit_window_secs")]
+    pub authenticate_window_secs: u64,
+}
+
+fn default_rate_limit_window_secs() -> u64 {
+    60
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            authenticate_max_requests: None,
+            authenticate_window_secs: default_rate_limit_window_secs(),
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct LoggingConfig {
+    /// Fraction of successful requests that get logged, from 0.0 to 1.0.
+    /// Failed requests are always logged.
+    #[serde(
+        default = "default_success_sample_rate",
+        deserialize_with = "deserialize_number_from_string"
+    )]
+    pub success_sample_rate: f64,
+    /// Per-route overrides of `success_sample_rate`, keyed by route pattern
+    /// such as `/users`.
+    #[serde(default)]
+    pub route_sample_rates: HashMap<String, f64>,
+}
+
+fn default_success_sample_rate() -> f64 {
+    1.0
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            success_sample_rate: default_success_sample_rate(),
+            route_sample_rates: HashMap::new(),
+        }
+    }
+}
+
+impl LoggingConfig {
+    pub fn sample_rate(&self, route: &str) -> f64 {
+        self.route_sample_rates
+            .get(route)
+            .copied()
+            .unwrap_or(self.success_sample_rate)
+    }
+}
+
+#[derive(serde::Deserialize, Clone, Default)]
+pub struct ChaosConfig {
+    #[serde(default)]
+    pub latency_ms: u64,
 }
 
 #[derive(serde::Deserialize, Clone)]
 pub struct Config {
+    #[serde(default)]
+    pub environment: Environment,
     pub application: ApplicationConfig,
     pub database: DatabaseConfig,
     pub jwt: JwtConfig,
+    #[serde(default)]
+    pub username: UsernameConfig,
+    #[serde(default)]
+    pub invite_code: InviteCodeConfig,
+    #[serde(default)]
+    pub referral: ReferralConfig,
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+    #[serde(default)]
+    pub logging: LoggingConfig,
+    #[serde(default)]
+    pub chaos: ChaosConfig,
+}
+
+#[derive(serde::Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+/// A problem with the config found by `Config::issues`.
+#[derive(serde::Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct ConfigIssue {
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl ConfigIssue {
+    fn error(message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Error,
+            message: message.into(),
+        }
+    }
+
+    fn warning(message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Warning,
+            message: message.into(),
+        }
+    }
+}
+
+impl Config {
+    /// Checks that values which serde can't validate on its own are usable.
+    /// Warnings are logged, the first error fails.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let issues = self.issues();
+        for issue in &issues {
+            if issue.severity == Severity::Warning {
+                tracing::warn!("{}", issue.message);
+            }
+        }
+
+        match issues.into_iter().find(|i| i.severity == Severity::Error) {
+            Some(issue) => anyhow::bail!(issue.message),
+            None => Ok(()),
+        }
+    }
+
+    /// Everything `validate` would warn about or fail on. Messages name the
+    /// offending keys but never include secret values.
+    pub fn issues(&self) -> Vec<ConfigIssue> {
+        let mut issues = Vec::new();
+
+        if self.username.min_length == 0 || self.username.min_length > self.username.max_length {
+            issues.push(ConfigIssue::error(
+                "username.min_length must be between 1 and username.max_length",
+            ));
+        }
+
+        if !(InviteCode::MIN_SUFFIX_LENGTH..=InviteCode::MAX_SUFFIX_LENGTH)
+            .contains(&self.invite_code.suffix_length)
+        {
+            issues.push(ConfigIssue::error(format!(
+                "invite_code.suffix_length must be between {} and {}",
+                InviteCode::MIN_SUFFIX_LENGTH,
+                InviteCode::MAX_SUFFIX_LENGTH
+            )));
+        }
+
+        // generated codes are up to 3 username characters plus the suffix
+        if self.invite_code.max_length < 3 + self.invite_code.suffix_length as usize {
+            issues.push(ConfigIssue::error(
+                "invite_code.max_length is too short for generated codes",
+            ));
+        }
+
+        if self.invite_code.max_generation_attempts == 0 {
+            issues.push(ConfigIssue::error(
+                "invite_code.max_generation_attempts must be greater than 0",
+            ));
+        }
+
+        if self.application.trusted_proxy_hops == 0 {
+            issues.push(ConfigIssue::error(
+                "application.trusted_proxy_hops must be greater than 0",
+            ));
+        }
+
+        if self.application.event_buffer_size == 0 {
+            issues.push(ConfigIssue::error(
+                "application.event_buffer_size must be greater than 0",
+            ));
+        }
+
+        if self.referral.max_tree_depth < 1 {
+            issues.push(ConfigIssue::error(
+                "referral.max_tree_depth must be greater than 0",
+            ));
+        }
+
+        if self.referral.max_tree_children == 0 {
+            issues.push(ConfigIssue::error(
+                "referral.max_tree_children must be greater than 0",
+            ));
+        }
+
+        if self.application.max_page_size < 1 {
+            issues.push(ConfigIssue::error(
+                "application.max_page_size must be greater than 0",
+            ));
+        }
+
+        if self.database.min_connections > self.database.max_connections {
+            issues.push(ConfigIssue::error(
+                "database.min_connections must not exceed database.max_connections",
+            ));
+        }
+
+        let mut sample_rates = std::iter::once(&self.logging.success_sample_rate)
+            .chain(self.logging.route_sample_rates.values());
+        if sample_rates.any(|rate| !(0.0..=1.0).contains(rate)) {
+            issues.push(ConfigIssue::error(
+                "logging sample rates must be between 0.0 and 1.0",
+            ));
+        }
+
+        if self.jwt.secret.expose_secret() == self.jwt.refresh_secret.expose_secret() {
+            issues.push(ConfigIssue::error(
+                "jwt.refresh_secret must differ from jwt.secret",
+            ));
+        }
+
+        self.jwt.secret_entropy_issues(&mut issues);
+        self.jwt.algorithm_issues(&mut issues);
+
+        let base_url = &self.application.public_base_url;
+        if !matches!(base_url.scheme(), "http" | "https") || !base_url.has_host() {
+            issues.push(ConfigIssue::error(
+                "application.public_base_url must be an absolute http(s) url",
+            ));
+        } else if self.environment == Environment::Production && is_local_host(base_url) {
+            // the checked-in default only works on a developer's machine, share
+            // links pointing at it are useless to anyone else
+            issues.push(ConfigIssue::error(format!(
+                "application.public_base_url must be the public address in production, not {}",
+                base_url
+            )));
+        }
+
+        issues
+    }
+
+    /// Optional behaviour clients may want to adapt to, derived from config.
+    /// Only ever reports on/off, never config values.
+    pub fn capabilities(&self) -> BTreeMap<&'static str, bool> {
+        BTreeMap::from([
+            ("refreshTokens", self.jwt.refresh_tokens),
+            ("lenientBearer", !self.jwt.strict_bearer),
+            ("sseResume", self.application.event_history_size > 0),
+            (
+                "unixTimestamps",
+                self.application.timestamp_format == TimestampFormat::Unix,
+            ),
+            ("websocket", self.application.websocket_enabled),
+        ])
+    }
+
+    /// Artificial latency to inject before every response. Only ever enabled
+    /// in debug mode and never in production, whatever the chaos config says.
+    pub fn chaos_latency(&self) -> Option<Duration> {
+        if self.chaos.latency_ms == 0
+            || !self.application.debug_mode
+            || self.environment == Environment::Production
+        {
+            return None;
+        }
+
+        Some(Duration::from_millis(self.chaos.latency_ms))
+    }
+}
+
+fn is_local_host(url: &Url) -> bool {
+    match url.host() {
+        Some(Host::Domain(domain)) => {
+            let domain = domain.to_ascii_lowercase();
+            domain == "localhost" || domain.ends_with(".localhost")
+        }
+        Some(Host::Ipv4(ip)) => ip.is_loopback() || ip.is_unspecified(),
+        Some(Host::Ipv6(ip)) => ip.is_loopback() || ip.is_unspecified(),
+        None => true,
+    }
 }
 
 pub fn get_config() -> Result<Config, config::ConfigError> {
@@ -106,6 +759,7 @@ pub fn get_config() -> Result<Config, config::ConfigError> {
     let config = config::Config::builder()
         .add_source(config::File::from(config_dir.join("base.yaml")))
         .add_source(config::File::from(config_dir.join(enviroment_filename)))
+        .set_override("environment", enviroment.as_str())?
         .add_source(
             config::Environment::with_prefix("APP")
                 .prefix_separator("_")
@@ -115,3 +769,122 @@ pub fn get_config() -> Result<Config, config::ConfigError> {
 
     config.try_deserialize::<Config>()
 }
+
+/// The checked-in base and local config, for tests that need a full `Config`.
+#[cfg(test)]
+pub(crate) fn test_config() -> Config {
+    config::Config::builder()
+        .add_source(config::File::from_str(
+            include_str!("../config/base.yaml"),
+            config::FileFormat::Yaml,
+        ))
+        .add_source(config::File::from_str(
+            include_str!("../config/local.yaml"),
+            config::FileFormat::Yaml,
+        ))
+        .build()
+        .and_then(|config| config.try_deserialize())
+        .expect("checked-in config should load")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capabilities_follow_config() {
+        let mut config = test_config();
+        let capabilities = config.capabilities();
+        assert!(capabilities["refreshTokens"]);
+        assert!(capabilities["websocket"]);
+        assert!(!capabilities["unixTimestamps"]);
+
+        config.jwt.refresh_tokens = false;
+        config.application.websocket_enabled = false;
+        config.application.timestamp_format = TimestampFormat::Unix;
+        let capabilities = config.capabilities();
+        assert!(!capabilities["refreshTokens"]);
+        assert!(!capabilities["websocket"]);
+        assert!(capabilities["unixTimestamps"]);
+    }
+
+    #[test]
+    fn capabilities_never_include_secrets() {
+        let config = test_config();
+        let capabilities = serde_json::to_string(&config.capabilities()).unwrap();
+        assert!(!capabilities.contains(config.jwt.secret.expose_secret()));
+    }
+
+    #[test]
+    fn low_entropy_secrets_are_flagged() {
+        assert!(JwtConfig::is_low_entropy(""));
+        assert!(JwtConfig::is_low_entropy("secret"));
+        assert!(JwtConfig::is_low_entropy(
+            "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa"
+        ));
+        // enough distinct characters, but repeated far too often
+        assert!(JwtConfig::is_low_entropy(&"0123456789".repeat(20)));
+    }
+
+    #[test]
+    fn random_looking_secrets_pass() {
+        assert!(!JwtConfig::is_low_entropy("0123456789"));
+        assert!(!JwtConfig::is_low_entropy(
+            "q8Zr2VxT1mLkP9sW4yBnC7hD3fJ6gK0a"
+        ));
+    }
+
+    #[test]
+    fn chaos_latency_only_applies_in_debug_outside_production() {
+        let mut config = test_config();
+        config.chaos.latency_ms = 250;
+        config.application.debug_mode = true;
+        config.environment = Environment::Local;
+        assert_eq!(config.chaos_latency(), Some(Duration::from_millis(250)));
+
+        config.application.debug_mode = false;
+        assert_eq!(config.chaos_latency(), None);
+
+        config.application.debug_mode = true;
+        config.environment = Environment::Production;
+        assert_eq!(config.chaos_latency(), None);
+    }
+
+    #[test]
+    fn zero_chaos_latency_is_disabled() {
+        let mut config = test_config();
+        config.chaos.latency_ms = 0;
+        config.application.debug_mode = true;
+        config.environment = Environment::Local;
+        assert_eq!(config.chaos_latency(), None);
+    }
+
+    #[test]
+    fn production_rejects_a_local_public_base_url() {
+        let mut config = test_config();
+        config.environment = Environment::Local;
+        assert!(config.validate().is_ok());
+
+        config.environment = Environment::Production;
+        for local in [
+            "http://localhost:3000",
+            "http://app.localhost",
+            "http://127.0.0.1:3000",
+            "http://[::1]:3000",
+            "http://0.0.0.0",
+        ] {
+            config.application.public_base_url = Url::parse(local).unwrap();
+            assert!(config.validate().is_err(), "{local}");
+        }
+
+        config.application.public_base_url = Url::parse("https://app.example").unwrap();
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn public_base_url_must_be_http() {
+        let mut config = test_config();
+        config.application.public_base_url = Url::parse("ftp://app.example").unwrap();
+        assert!(config.validate().is_err());
+    }
+}
@@ -1,20 +1,99 @@
-use axum::{http::StatusCode, response::IntoResponse, Json};
+use axum::{
+    extract::rejection::QueryRejection,
+    http::{header, StatusCode},
+    response::IntoResponse,
+    Json,
+};
 use serde_json::json;
+use std::fmt::Display;
 
+#[derive(Debug)]
 pub enum DatabaseError {
-    ServerError,
+    NotFound,
+    Conflict,
+    /// A new user's invite code clashed with an existing one, the caller can
+    /// generate another and retry.
+    InviteCodeTaken,
+    /// The database couldn't be reached or dropped the connection.
+    ConnectionError,
+    /// The database rejected or failed the query.
+    QueryError,
 }
 
 pub enum ApiError {
     InvalidInviteCode,
+    /// Carries the reason, returned to the client as `details`.
+    InvalidUsername(String),
+    InvalidCursor,
+    InvalidQuery(String),
+    BatchTooLarge,
+    InvalidIdempotencyKey,
+    IdempotencyKeyReused,
+    IdempotencyKeyInProgress,
     ServerError,
+    NotFound,
+    DatabaseUnavailable,
     AuthenticationError,
+    Forbidden,
+    AccountDeactivated,
+    TokenExpired,
+    InvalidToken,
+    Conflict,
+    InviteCodeExhausted,
+    MaintenanceMode(u64),
+    RateLimited(u64),
 }
 
 impl From<DatabaseError> for ApiError {
     fn from(value: DatabaseError) -> Self {
         match value {
-            DatabaseError::ServerError => Self::ServerError,
+            DatabaseError::NotFound => Self::NotFound,
+            DatabaseError::Conflict | DatabaseError::InviteCodeTaken => Self::Conflict,
+            DatabaseError::ConnectionError => Self::DatabaseUnavailable,
+            DatabaseError::QueryError => Self::ServerError,
+        }
+    }
+}
+
+impl From<QueryRejection> for ApiError {
+    fn from(value: QueryRejection) -> Self {
+        tracing::info!("rejected query >>> {}", value.body_text());
+        Self::InvalidQuery(value.body_text())
+    }
+}
+
+impl ApiError {
+    /// Stable, machine-readable identifier for the error. Clients should
+    /// branch on this rather than the message, which may be reworded.
+    fn code(&self) -> &'static str {
+        match self {
+            Self::InvalidInviteCode => "INVALID_INVITE_CODE",
+            Self::InvalidUsername(_) => "INVALID_USERNAME",
+            Self::InvalidCursor => "INVALID_CURSOR",
+            Self::InvalidQuery(_) => "INVALID_QUERY",
+            Self::BatchTooLarge => "BATCH_TOO_LARGE",
+            Self::InvalidIdempotencyKey => "INVALID_IDEMPOTENCY_KEY",
+            Self::IdempotencyKeyReused => "IDEMPOTENCY_KEY_REUSED",
+            Self::IdempotencyKeyInProgress => "IDEMPOTENCY_KEY_IN_PROGRESS",
+            Self::ServerError => "INTERNAL_ERROR",
+            Self::NotFound => "NOT_FOUND",
+            Self::DatabaseUnavailable => "SERVICE_UNAVAILABLE",
+            Self::AuthenticationError => "UNAUTHORIZED",
+            Self::Forbidden => "FORBIDDEN",
+            Self::AccountDeactivated => "ACCOUNT_DEACTIVATED",
+            Self::TokenExpired => "TOKEN_EXPIRED",
+            Self::InvalidToken => "TOKEN_INVALID",
+            Self::Conflict => "CONFLICT",
+            Self::InviteCodeExhausted => "INVITE_CODE_EXHAUSTED",
+            Self::MaintenanceMode(_) => "MAINTENANCE_MODE",
+            Self::RateLimited(_) => "RATE_LIMITED",
+        }
+    }
+
+    fn details(&self) -> Option<&str> {
+        match self {
+            Self::InvalidUsername(details) | Self::InvalidQuery(details) => Some(details),
+            _ => None,
         }
     }
 }
@@ -23,13 +102,63 @@ impl IntoResponse for ApiError {
     fn into_response(self) -> axum::response::Response {
         let (status, error_message) = match self {
             Self::InvalidInviteCode => (StatusCode::BAD_REQUEST, "Invalid invite code"),
+            Self::InvalidUsername(_) => (StatusCode::BAD_REQUEST, "Invalid username"),
+            Self::InvalidCursor => (StatusCode::BAD_REQUEST, "Invalid cursor"),
+            Self::InvalidQuery(_) => (StatusCode::BAD_REQUEST, "Invalid query parameters"),
+            Self::BatchTooLarge => (StatusCode::BAD_REQUEST, "Too many entries in batch"),
+            Self::InvalidIdempotencyKey => (StatusCode::BAD_REQUEST, "Invalid Idempotency-Key"),
+            Self::IdempotencyKeyReused => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "Idempotency-Key was already used for a different request",
+            ),
+            Self::IdempotencyKeyInProgress => (
+                StatusCode::CONFLICT,
+                "A request with this Idempotency-Key is still in progress",
+            ),
             Self::ServerError => (StatusCode::INTERNAL_SERVER_ERROR, "Something went wrong"),
+            Self::NotFound => (StatusCode::NOT_FOUND, "Resource not found"),
+            Self::DatabaseUnavailable => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Service temporarily unavailable, try again later",
+            ),
             Self::AuthenticationError => (StatusCode::UNAUTHORIZED, "Authentication failed"),
+            Self::Forbidden => (StatusCode::FORBIDDEN, "Forbidden"),
+            Self::AccountDeactivated => (StatusCode::FORBIDDEN, "Account is deactivated"),
+            // machine-readable so clients know whether to refresh or log in again
+            Self::TokenExpired => (StatusCode::UNAUTHORIZED, "token_expired"),
+            Self::InvalidToken => (StatusCode::UNAUTHORIZED, "invalid_token"),
+            Self::Conflict => (StatusCode::CONFLICT, "Resource already exists"),
+            Self::InviteCodeExhausted => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Could not generate a unique invite code",
+            ),
+            Self::MaintenanceMode(_) => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Service is under maintenance, try again later",
+            ),
+            Self::RateLimited(_) => (
+                StatusCode::TOO_MANY_REQUESTS,
+                "Too many requests, try again later",
+            ),
         };
 
-        let body = Json(json!({
-            "error": error_message
-        }));
+        let mut body = json!({
+            "error": error_message,
+            "code": self.code(),
+        });
+        if let Some(details) = self.details() {
+            body["details"] = details.into();
+        }
+        let body = Json(body);
+
+        if let Self::MaintenanceMode(retry_after) | Self::RateLimited(retry_after) = self {
+            return (
+                status,
+                [(header::RETRY_AFTER, retry_after.to_string())],
+                body,
+            )
+                .into_response();
+        }
 
         (status, body).into_response()
     }
@@ -39,10 +168,158 @@ impl IntoResponse for ApiError {
 pub enum JWTError {
     GenerationFailed(jsonwebtoken::errors::ErrorKind),
     DecodeFailed(jsonwebtoken::errors::ErrorKind),
+    WrongTokenType,
 }
 
 impl From<JWTError> for ApiError {
-    fn from(_value: JWTError) -> Self {
-        Self::AuthenticationError
+    fn from(value: JWTError) -> Self {
+        match value {
+            JWTError::GenerationFailed(_) => Self::AuthenticationError,
+            JWTError::DecodeFailed(jsonwebtoken::errors::ErrorKind::ExpiredSignature) => {
+                Self::TokenExpired
+            }
+            JWTError::DecodeFailed(_) | JWTError::WrongTokenType => Self::InvalidToken,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ValidationError {
+    Empty,
+    TooShort(usize),
+    TooLong(usize),
+    InvalidCharacters,
+}
+
+impl Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Empty => write!(f, "must not be empty"),
+            Self::TooShort(min) => write!(f, "must be at least {} characters", min),
+            Self::TooLong(max) => write!(f, "must be at most {} characters", max),
+            Self::InvalidCharacters => write!(f, "contains invalid characters"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::HttpBody;
+    use serde_json::Value;
+
+    async fn json_body(response: axum::response::Response) -> Value {
+        let mut body = response.into_body();
+        let mut bytes = Vec::new();
+        while let Some(chunk) = body.data().await {
+            bytes.extend_from_slice(&chunk.unwrap());
+        }
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    async fn render(error: ApiError) -> (StatusCode, Value) {
+        let response = error.into_response();
+        (response.status(), json_body(response).await)
+    }
+
+    #[tokio::test]
+    async fn errors_map_to_status_and_code() {
+        let cases = [
+            (ApiError::InvalidInviteCode, 400, "INVALID_INVITE_CODE"),
+            (ApiError::InvalidCursor, 400, "INVALID_CURSOR"),
+            (ApiError::BatchTooLarge, 400, "BATCH_TOO_LARGE"),
+            (
+                ApiError::IdempotencyKeyReused,
+                422,
+                "IDEMPOTENCY_KEY_REUSED",
+            ),
+            (
+                ApiError::IdempotencyKeyInProgress,
+                409,
+                "IDEMPOTENCY_KEY_IN_PROGRESS",
+            ),
+            (ApiError::ServerError, 500, "INTERNAL_ERROR"),
+            (ApiError::NotFound, 404, "NOT_FOUND"),
+            (ApiError::DatabaseUnavailable, 503, "SERVICE_UNAVAILABLE"),
+            (ApiError::AuthenticationError, 401, "UNAUTHORIZED"),
+            (ApiError::Forbidden, 403, "FORBIDDEN"),
+            (ApiError::AccountDeactivated, 403, "ACCOUNT_DEACTIVATED"),
+            (ApiError::TokenExpired, 401, "TOKEN_EXPIRED"),
+            (ApiError::InvalidToken, 401, "TOKEN_INVALID"),
+            (ApiError::Conflict, 409, "CONFLICT"),
+            (ApiError::InviteCodeExhausted, 500, "INVITE_CODE_EXHAUSTED"),
+        ];
+
+        for (error, status, code) in cases {
+            let (actual_status, body) = render(error).await;
+            assert_eq!(actual_status.as_u16(), status, "{code}");
+            assert_eq!(body["code"], code);
+            assert!(body["error"].is_string(), "{code}");
+            assert!(body.get("details").is_none(), "{code}");
+        }
+    }
+
+    #[tokio::test]
+    async fn validation_errors_carry_details() {
+        let (status, body) = render(ApiError::InvalidUsername("too short".into())).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["code"], "INVALID_USERNAME");
+        assert_eq!(body["details"], "too short");
+
+        let (status, body) = render(ApiError::InvalidQuery("bad limit".into())).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["code"], "INVALID_QUERY");
+        assert_eq!(body["details"], "bad limit");
+    }
+
+    #[tokio::test]
+    async fn throttling_errors_set_retry_after() {
+        let cases = [
+            (
+                ApiError::MaintenanceMode(120),
+                503,
+                "MAINTENANCE_MODE",
+                "120",
+            ),
+            (ApiError::RateLimited(30), 429, "RATE_LIMITED", "30"),
+        ];
+
+        for (error, status, code, retry_after) in cases {
+            let response = error.into_response();
+            assert_eq!(response.status().as_u16(), status);
+            assert_eq!(response.headers()[header::RETRY_AFTER], retry_after);
+            assert_eq!(json_body(response).await["code"], code);
+        }
+    }
+
+    #[test]
+    fn database_errors_map_to_api_errors() {
+        assert_eq!(ApiError::from(DatabaseError::NotFound).code(), "NOT_FOUND");
+        assert_eq!(ApiError::from(DatabaseError::Conflict).code(), "CONFLICT");
+        assert_eq!(
+            ApiError::from(DatabaseError::InviteCodeTaken).code(),
+            "CONFLICT"
+        );
+        assert_eq!(
+            ApiError::from(DatabaseError::ConnectionError).code(),
+            "SERVICE_UNAVAILABLE"
+        );
+        assert_eq!(
+            ApiError::from(DatabaseError::QueryError).code(),
+            "INTERNAL_ERROR"
+        );
+    }
+
+    #[test]
+    fn expired_tokens_are_told_apart_from_invalid_ones() {
+        let expired = JWTError::DecodeFailed(jsonwebtoken::errors::ErrorKind::ExpiredSignature);
+        assert_eq!(ApiError::from(expired).code(), "TOKEN_EXPIRED");
+
+        let invalid = JWTError::DecodeFailed(jsonwebtoken::errors::ErrorKind::InvalidSignature);
+        assert_eq!(ApiError::from(invalid).code(), "TOKEN_INVALID");
+        assert_eq!(
+            ApiError::from(JWTError::WrongTokenType).code(),
+            "TOKEN_INVALID"
+        );
     }
 }
@@ -1,5 +1,7 @@
 use super::fields::{User, Username};
 use serde::Serialize;
+use std::{collections::VecDeque, sync::Mutex};
+use tokio::sync::broadcast;
 
 #[derive(Serialize, Clone)]
 pub struct NewReferralEvent {
@@ -7,10 +9,250 @@ pub struct NewReferralEvent {
     pub referred_user: Username,
 }
 
+#[derive(Serialize, Clone)]
+pub struct ReferralMilestoneEvent {
+    pub user: Username,
+    pub total: i64,
+}
+
 #[derive(Serialize, Clone)]
 #[serde(tag = "type", content = "data")]
 pub enum AppEvent {
     NewLogin(User),
     NewRegister(User),
     NewReferral(NewReferralEvent),
+    ReferralMilestone(ReferralMilestoneEvent),
+}
+
+impl AppEvent {
+    /// Whether `username` should receive this event: their own logins and
+    /// registrations, and referrals and milestones credited to them.
+    pub fn is_relevant_to(&self, username: &Username) -> bool {
+        match self {
+            Self::NewLogin(user) | Self::NewRegister(user) => &user.username == username,
+            Self::NewReferral(referral) => &referral.referrer == username,
+            Self::ReferralMilestone(milestone) => &milestone.user == username,
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::NewLogin(_) => "NewLogin",
+            Self::NewRegister(_) => "NewRegister",
+            Self::NewReferral(_) => "NewReferral",
+            Self::ReferralMilestone(_) => "ReferralMilestone",
+        }
+    }
+}
+
+/// An event as delivered to subscribers, tagged with its position in the
+/// event log so reconnecting clients can resume after it.
+#[derive(Clone)]
+pub struct EventEnvelope {
+    pub id: u64,
+    pub event: AppEvent,
+}
+
+pub enum Replay {
+    /// Buffered events the subscriber missed, oldest first.
+    Events(Vec<EventEnvelope>),
+    /// The subscriber missed events that are no longer buffered and should
+    /// refetch its state. Carries the latest event id.
+    Resync(u64),
+}
+
+struct EventLogInner {
+    next_id: u64,
+    events: VecDeque<EventEnvelope>,
+}
+
+/// Numbers published events and keeps the most recent ones so subscribers
+/// can catch up after a reconnect.
+pub struct EventLog {
+    capacity: usize,
+    inner: Mutex<EventLogInner>,
+}
+
+impl EventLog {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            inner: Mutex::new(EventLogInner {
+                next_id: 1,
+                events: VecDeque::with_capacity(capacity),
+            }),
+        }
+    }
+
+    pub fn publish(&self, tx: &broadcast::Sender<EventEnvelope>, event: AppEvent) {
+        let mut inner = self.inner.lock().unwrap();
+        let envelope = EventEnvelope {
+            id: inner.next_id,
+            event,
+        };
+        inner.next_id += 1;
+
+        if self.capacity > 0 {
+            if inner.events.len() == self.capacity {
+                inner.events.pop_front();
+            }
+            inner.events.push_back(envelope.clone());
+        }
+
+        // sending under the lock keeps ids in order on the channel and lets
+        // `subscribe` snapshot the buffer without missing anything
+        let _ = tx.send(envelope);
+    }
+
+    /// Subscribes to live events, along with whatever the subscriber missed
+    /// since `last_event_id`.
+    pub fn subscribe(
+        &self,
+        tx: &broadcast::Sender<EventEnvelope>,
+        last_event_id: Option<u64>,
+    ) -> (broadcast::Receiver<EventEnvelope>, Replay) {
+        let inner = self.inner.lock().unwrap();
+        let rx = tx.subscribe();
+        let latest = inner.next_id - 1;
+
+        let replay = match last_event_id {
+            None => Replay::Events(vec![]),
+            Some(last) if last == latest => Replay::Events(vec![]),
+            // an id we never issued, e.g. from before a restart
+            Some(last) if last > latest => Replay::Resync(latest),
+            Some(last) => match inner.events.front() {
+                Some(oldest) if oldest.id <= last + 1 => Replay::Events(
+                    inner
+                        .events
+                        .iter()
+                        .filter(|e| e.id > last)
+                        .cloned()
+                        .collect(),
+                ),
+                _ => Replay::Resync(latest),
+            },
+        };
+
+        (rx, replay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn referral(n: u64) -> AppEvent {
+        AppEvent::NewReferral(NewReferralEvent {
+            referrer: Username::from("referrer".to_owned()),
+            referred_user: Username::from(format!("user{n}")),
+        })
+    }
+
+    fn publish(log: &EventLog, tx: &broadcast::Sender<EventEnvelope>, count: u64) {
+        for n in 0..count {
+            log.publish(tx, referral(n));
+        }
+    }
+
+    fn replayed_ids(replay: Replay) -> Vec<u64> {
+        match replay {
+            Replay::Events(events) => events.iter().map(|e| e.id).collect(),
+            Replay::Resync(latest) => panic!("expected events, got resync at {latest}"),
+        }
+    }
+
+    #[test]
+    fn replays_events_after_the_last_seen_id() {
+        let (tx, _rx) = broadcast::channel(16);
+        let log = EventLog::new(8);
+        publish(&log, &tx, 5);
+
+        let (_, replay) = log.subscribe(&tx, Some(2));
+        assert_eq!(replayed_ids(replay), vec![3, 4, 5]);
+
+        let (_, replay) = log.subscribe(&tx, Some(5));
+        assert!(replayed_ids(replay).is_empty());
+
+        let (_, replay) = log.subscribe(&tx, None);
+        assert!(replayed_ids(replay).is_empty());
+    }
+
+    #[test]
+    fn replays_from_the_oldest_buffered_event() {
+        let (tx, _rx) = broadcast::channel(16);
+        let log = EventLog::new(3);
+        publish(&log, &tx, 5);
+
+        // 3, 4 and 5 are still buffered, so a client that saw 2 missed nothing
+        let (_, replay) = log.subscribe(&tx, Some(2));
+        assert_eq!(replayed_ids(replay), vec![3, 4, 5]);
+    }
+
+    #[test]
+    fn resyncs_once_missed_events_were_dropped() {
+        let (tx, _rx) = broadcast::channel(16);
+        let log = EventLog::new(3);
+        publish(&log, &tx, 5);
+
+        let (_, replay) = log.subscribe(&tx, Some(1));
+        assert!(matches!(replay, Replay::Resync(5)));
+    }
+
+    #[test]
+    fn resyncs_on_ids_that_were_never_issued() {
+        let (tx, _rx) = broadcast::channel(16);
+        let log = EventLog::new(3);
+        publish(&log, &tx, 2);
+
+        let (_, replay) = log.subscribe(&tx, Some(40));
+        assert!(matches!(replay, Replay::Resync(2)));
+    }
+
+    #[test]
+    fn without_a_buffer_any_gap_resyncs() {
+        let (tx, _rx) = broadcast::channel(16);
+        let log = EventLog::new(0);
+        publish(&log, &tx, 3);
+
+        let (_, replay) = log.subscribe(&tx, Some(3));
+        assert!(replayed_ids(replay).is_empty());
+
+        let (_, replay) = log.subscribe(&tx, Some(2));
+        assert!(matches!(replay, Replay::Resync(3)));
+    }
+
+    #[test]
+    fn live_events_follow_the_replay() {
+        let (tx, _rx) = broadcast::channel(16);
+        let log = EventLog::new(8);
+        publish(&log, &tx, 2);
+
+        let (mut rx, replay) = log.subscribe(&tx, Some(1));
+        assert_eq!(replayed_ids(replay), vec![2]);
+
+        publish(&log, &tx, 1);
+        assert_eq!(rx.try_recv().unwrap().id, 3);
+    }
+
+    #[test]
+    fn referrals_are_only_relevant_to_the_referrer() {
+        let event = AppEvent::NewReferral(NewReferralEvent {
+            referrer: Username::from("alice".to_owned()),
+            referred_user: Username::from("bob".to_owned()),
+        });
+        assert!(event.is_relevant_to(&Username::from("alice".to_owned())));
+        // the referred user hears about their own registration instead
+        assert!(!event.is_relevant_to(&Username::from("bob".to_owned())));
+        assert!(!event.is_relevant_to(&Username::from("carol".to_owned())));
+    }
+
+    #[test]
+    fn milestones_are_only_relevant_to_the_user_reaching_them() {
+        let event = AppEvent::ReferralMilestone(ReferralMilestoneEvent {
+            user: Username::from("alice".to_owned()),
+            total: 5,
+        });
+        assert!(event.is_relevant_to(&Username::from("alice".to_owned())));
+        assert!(!event.is_relevant_to(&Username::from("carol".to_owned())));
+    }
 }
@@ -1,13 +1,50 @@
 use rand::{distributions::Uniform, prelude::Distribution};
 use serde::{Deserialize, Serialize};
 use std::fmt::Display;
+use time::OffsetDateTime;
 
-use super::model::DbUser;
+use super::{errors::ValidationError, model::DbUser, timestamp};
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct Username(String);
 
 impl Username {
+    pub const DEFAULT_MIN_LENGTH: usize = 3;
+    pub const DEFAULT_MAX_LENGTH: usize = 32;
+
+    /// Validates a username received from a client. Surrounding whitespace is
+    /// trimmed and only alphanumerics, `_` and `-` are allowed. Casing is kept
+    /// for display; uniqueness and lookups ignore it.
+    pub fn parse(
+        value: String,
+        min_length: usize,
+        max_length: usize,
+    ) -> Result<Self, ValidationError> {
+        let value = value.trim();
+        let length = value.chars().count();
+
+        if length == 0 {
+            return Err(ValidationError::Empty);
+        }
+
+        if length < min_length {
+            return Err(ValidationError::TooShort(min_length));
+        }
+
+        if length > max_length {
+            return Err(ValidationError::TooLong(max_length));
+        }
+
+        if !value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            return Err(ValidationError::InvalidCharacters);
+        }
+
+        Ok(Self(value.to_owned()))
+    }
+
     pub fn inner(&self) -> String {
         self.0.to_owned()
     }
@@ -31,33 +68,127 @@ impl Display for Username {
     }
 }
 
+/// How invite codes are cased, both when generated and when received.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum CasePolicy {
+    #[default]
+    Preserve,
+    Lower,
+    Upper,
+}
+
+impl CasePolicy {
+    pub fn apply(&self, value: &str) -> String {
+        match self {
+            Self::Preserve => value.to_owned(),
+            Self::Lower => value.to_lowercase(),
+            Self::Upper => value.to_uppercase(),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(try_from = "String")]
 pub struct InviteCode(String);
 
 impl InviteCode {
-    pub fn new(username: &str) -> Self {
-        Self(format!(
+    pub const DEFAULT_MAX_LENGTH: usize = 32;
+    pub const MIN_SUFFIX_LENGTH: u32 = 4;
+    pub const MAX_SUFFIX_LENGTH: u32 = 18;
+
+    pub fn new(username: &str, suffix_length: u32, case: CasePolicy) -> Self {
+        // usernames can be shorter than the prefix or contain multi-byte
+        // characters, so take chars rather than slicing bytes
+        let prefix: String = username.chars().take(3).collect();
+        Self(case.apply(&format!(
             "{}{}",
-            &username[..=2],
-            Self::generate_invite_code_digit()
-        ))
+            prefix,
+            Self::generate_invite_code_digit(suffix_length)
+        )))
+    }
+
+    /// Shortest suffix of at least `min_length` digits with room for a hundred
+    /// times `user_count` codes, so a random pick rarely hits a taken one.
+    pub fn suffix_length_for(user_count: i64, min_length: u32, max_length: u32) -> u32 {
+        let needed = user_count.max(1) as u128 * 100;
+        let mut length = min_length;
+        // a suffix of `length` digits has 9 * 10^(length - 1) values
+        while length < max_length && 9 * 10u128.pow(length - 1) < needed {
+            length += 1;
+        }
+        length
+    }
+
+    /// Normalizes and validates an invite code received from a client before
+    /// it is used in any lookup.
+    pub fn parse(
+        value: String,
+        max_length: usize,
+        case: CasePolicy,
+    ) -> Result<Self, ValidationError> {
+        let value = case.apply(value.trim());
+        if value.is_empty() {
+            return Err(ValidationError::Empty);
+        }
+
+        if value.chars().count() > max_length {
+            return Err(ValidationError::TooLong(max_length));
+        }
+
+        if !value
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+        {
+            return Err(ValidationError::InvalidCharacters);
+        }
+
+        Ok(Self(value))
     }
 
     pub fn inner(&self) -> String {
         self.0.to_owned()
     }
 
-    fn generate_invite_code_digit() -> String {
+    fn generate_invite_code_digit(length: u32) -> String {
         let mut rng = rand::thread_rng();
-        let uni_sample = Uniform::from(1001..=9999);
+        let uni_sample = Uniform::from(10u64.pow(length - 1)..10u64.pow(length));
         let code = uni_sample.sample(&mut rng);
         code.to_string()
     }
 }
 
-impl From<String> for InviteCode {
+impl TryFrom<String> for InviteCode {
+    type Error = ValidationError;
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Self::parse(value, Self::DEFAULT_MAX_LENGTH, CasePolicy::default())
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    #[default]
+    Member,
+    Admin,
+}
+
+impl Role {
+    /// How the role is stored in `users.role`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Member => "member",
+            Self::Admin => "admin",
+        }
+    }
+}
+
+impl From<String> for Role {
     fn from(value: String) -> Self {
-        Self(value)
+        match value.as_str() {
+            "admin" => Self::Admin,
+            _ => Self::Member,
+        }
     }
 }
 
@@ -68,22 +199,195 @@ pub struct User {
     pub invite_code: InviteCode,
     pub referred_by: Option<Username>,
     pub referrals: i64,
+    #[serde(serialize_with = "timestamp::serialize")]
+    pub joined_at: OffsetDateTime,
+    #[serde(default)]
+    pub role: Role,
+    #[serde(
+        default,
+        serialize_with = "timestamp::serialize_option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub deactivated_at: Option<OffsetDateTime>,
 }
 
 impl From<DbUser> for User {
     fn from(value: DbUser) -> Self {
         Self {
             username: value.username.into(),
-            invite_code: value.invite_code.into(),
-            referred_by: value.referred_by.map(|r| Username::from(r)),
+            invite_code: InviteCode(value.invite_code),
+            referred_by: value.referred_by.map(Username::from),
             referrals: value.referrals.unwrap_or(0),
+            joined_at: value.created_on,
+            role: value.role.into(),
+            deactivated_at: value.deactivated_at,
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenType {
+    Access,
+    Refresh,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Claims {
+    pub jti: String,
     pub sub: String,
     pub iss: String,
+    /// Tokens issued before this field existed read as issued at 0.
+    #[serde(default)]
+    pub iat: usize,
     pub exp: usize,
+    pub token_type: TokenType,
+    /// Shared by the access and refresh tokens of one login, and by access
+    /// tokens refreshed from it, so logout can revoke them all. Empty for
+    /// tokens issued before sessions existed.
+    #[serde(default)]
+    pub sid: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_username(value: &str) -> Result<Username, ValidationError> {
+        Username::parse(
+            value.to_owned(),
+            Username::DEFAULT_MIN_LENGTH,
+            Username::DEFAULT_MAX_LENGTH,
+        )
+    }
+
+    #[test]
+    fn username_length_bounds_are_inclusive() {
+        assert!(parse_username("abc").is_ok());
+        assert!(parse_username(&"a".repeat(32)).is_ok());
+        assert!(matches!(
+            parse_username("ab"),
+            Err(ValidationError::TooShort(3))
+        ));
+        assert!(matches!(
+            parse_username(&"a".repeat(33)),
+            Err(ValidationError::TooLong(32))
+        ));
+        assert!(matches!(parse_username("   "), Err(ValidationError::Empty)));
+    }
+
+    #[test]
+    fn username_rejects_characters_outside_the_charset() {
+        for name in [
+            "al ice",
+            "alice!",
+            "al.ice",
+            "ali'; drop table users;--",
+            "álice",
+        ] {
+            assert!(
+                matches!(
+                    parse_username(name),
+                    Err(ValidationError::InvalidCharacters)
+                ),
+                "{} should be rejected",
+                name
+            );
+        }
+        assert!(parse_username("al_ice-2").is_ok());
+    }
+
+    #[test]
+    fn username_is_trimmed_before_checking_length() {
+        assert_eq!(parse_username("  alice \n").unwrap().as_ref(), "alice");
+        assert!(matches!(
+            parse_username("  ab  "),
+            Err(ValidationError::TooShort(3))
+        ));
+    }
+
+    #[test]
+    fn invite_code_handles_short_and_multi_byte_usernames() {
+        for (username, prefix) in [("ab", "ab"), ("é", "é"), ("", ""), ("zoë-x", "zoë")] {
+            let code = InviteCode::new(username, 4, CasePolicy::Preserve).0;
+            let suffix = code
+                .strip_prefix(prefix)
+                .unwrap_or_else(|| panic!("{} should start with {}", code, prefix));
+            assert_eq!(suffix.len(), 4);
+            assert!(suffix.chars().all(|c| c.is_ascii_digit()));
+        }
+    }
+
+    #[test]
+    fn invite_code_is_trimmed_and_cased_by_policy() {
+        let parse = |case| {
+            InviteCode::parse(" ABC1234 ".to_owned(), 32, case)
+                .unwrap()
+                .0
+        };
+        assert_eq!(parse(CasePolicy::Preserve), "ABC1234");
+        assert_eq!(parse(CasePolicy::Lower), "abc1234");
+        assert_eq!(parse(CasePolicy::Upper), "ABC1234");
+        assert_eq!(
+            InviteCode::parse(" aBc1234".to_owned(), 32, CasePolicy::Upper)
+                .unwrap()
+                .0,
+            "ABC1234"
+        );
+    }
+
+    #[test]
+    fn invite_code_rejects_oversized_and_malformed_input() {
+        let parse = |code: &str| InviteCode::parse(code.to_owned(), 32, CasePolicy::Preserve);
+        assert!(matches!(
+            parse(&"a".repeat(33)),
+            Err(ValidationError::TooLong(32))
+        ));
+        assert!(matches!(
+            parse("abc'; --"),
+            Err(ValidationError::InvalidCharacters)
+        ));
+        assert!(matches!(parse("  "), Err(ValidationError::Empty)));
+        assert!(parse(&"a".repeat(32)).is_ok());
+    }
+
+    #[test]
+    fn suffix_length_grows_with_user_count() {
+        let small = InviteCode::suffix_length_for(10, 4, 18);
+        let large = InviteCode::suffix_length_for(10_000_000, 4, 18);
+        assert_eq!(small, 4);
+        assert!(large > small);
+        assert_eq!(
+            InviteCode::new("alice", large, CasePolicy::Preserve)
+                .0
+                .len(),
+            3 + large as usize
+        );
+    }
+
+    #[test]
+    fn suffix_length_stays_within_bounds() {
+        assert_eq!(InviteCode::suffix_length_for(0, 4, 18), 4);
+        assert_eq!(InviteCode::suffix_length_for(i64::MAX, 4, 8), 8);
+    }
+
+    #[test]
+    fn users_serialize_with_camel_case_keys() {
+        let user = User {
+            username: Username::from("alice".to_owned()),
+            invite_code: InviteCode::new("alice", 4, CasePolicy::Preserve),
+            referred_by: Some(Username::from("bob".to_owned())),
+            referrals: 2,
+            joined_at: OffsetDateTime::UNIX_EPOCH,
+            role: Role::Member,
+            deactivated_at: None,
+        };
+
+        let json = serde_json::to_value(&user).unwrap();
+        assert_eq!(json["joinedAt"], "1970-01-01T00:00:00Z");
+        assert_eq!(json["referredBy"], "bob");
+        assert!(json.get("inviteCode").is_some());
+        assert!(json.get("joined_at").is_none());
+        assert!(json.get("referred_by").is_none());
+    }
 }
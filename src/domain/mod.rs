@@ -2,3 +2,4 @@ pub mod errors;
 pub mod events;
 pub mod fields;
 pub mod model;
+pub mod timestamp;
@@ -11,4 +11,33 @@ pub struct DbUser {
     pub(crate) referred_by: Option<String>,
     pub(crate) referrals: Option<i64>,
     pub(crate) created_on: OffsetDateTime,
+    pub(crate) role: String,
+    pub(crate) deactivated_at: Option<OffsetDateTime>,
+}
+
+#[derive(FromRow)]
+pub struct DbRankedUser {
+    #[sqlx(flatten)]
+    pub user: DbUser,
+    pub rank: i64,
+}
+
+#[derive(FromRow)]
+pub struct DbTreeUser {
+    #[sqlx(flatten)]
+    pub user: DbUser,
+    pub depth: i32,
+}
+
+#[derive(FromRow)]
+pub struct DbLoginEvent {
+    pub ip_address: Option<String>,
+    pub logged_in_at: OffsetDateTime,
+}
+
+#[derive(FromRow)]
+pub struct DbCohortRank {
+    pub rank: i64,
+    pub cohort_size: i64,
+    pub cohort_start: OffsetDateTime,
 }
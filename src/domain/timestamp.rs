@@ -0,0 +1,149 @@
+//! Serde helper for timestamps in API responses, used with
+//! `#[serde(serialize_with = "timestamp::serialize")]`. Whether they go out as
+//! RFC 3339 strings or unix epoch seconds comes from the app's config: serde
+//! can't hand `serialize_with` any state, so the format is scoped around the
+//! serialization with `scope` or `with_format`. Outside of both, timestamps
+//! are RFC 3339.
+
+use serde::{Deserialize, Serializer};
+use std::future::Future;
+use time::OffsetDateTime;
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum TimestampFormat {
+    #[default]
+    Rfc3339,
+    Unix,
+}
+
+tokio::task_local! {
+    static FORMAT: TimestampFormat;
+}
+
+/// Runs `f` with timestamps serialized as `format`.
+pub fn with_format<R>(format: TimestampFormat, f: impl FnOnce() -> R) -> R {
+    FORMAT.sync_scope(format, f)
+}
+
+/// Like `with_format` for a future, e.g. a handler whose JSON response is
+/// serialized before it completes.
+pub async fn scope<F: Future>(format: TimestampFormat, f: F) -> F::Output {
+    FORMAT.scope(format, f).await
+}
+
+fn format() -> TimestampFormat {
+    FORMAT.try_with(|format| *format).unwrap_or_default()
+}
+
+pub fn serialize<S: Serializer>(value: &OffsetDateTime, serializer: S) -> Result<S::Ok, S::Error> {
+    match format() {
+        TimestampFormat::Unix => serializer.serialize_i64(value.unix_timestamp()),
+        TimestampFormat::Rfc3339 => time::serde::rfc3339::serialize(value, serializer),
+    }
+}
+
+pub fn serialize_option<S: Serializer>(
+    value: &Option<OffsetDateTime>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match value {
+        Some(value) => serialize(value, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Serialize;
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct Joined {
+        #[serde(
+            serialize_with = "serialize",
+            deserialize_with = "time::serde::rfc3339::deserialize"
+        )]
+        joined_at: OffsetDateTime,
+    }
+
+    #[derive(Serialize)]
+    struct MaybeJoined {
+        #[serde(serialize_with = "serialize_option")]
+        joined_at: Option<OffsetDateTime>,
+    }
+
+    /// 2023-10-01T12:30:45Z
+    const JOINED_AT_SECS: i64 = 1696163445;
+
+    fn joined_at() -> OffsetDateTime {
+        OffsetDateTime::from_unix_timestamp(JOINED_AT_SECS).unwrap()
+    }
+
+    #[test]
+    fn rfc3339_round_trips() {
+        let json = with_format(TimestampFormat::Rfc3339, || {
+            serde_json::to_string(&Joined {
+                joined_at: joined_at(),
+            })
+        })
+        .unwrap();
+        assert_eq!(json, r#"{"joinedAt":"2023-10-01T12:30:45Z"}"#);
+
+        let parsed: Joined = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.joined_at, joined_at());
+    }
+
+    #[test]
+    fn unix_epoch_round_trips() {
+        let json = with_format(TimestampFormat::Unix, || {
+            serde_json::to_value(MaybeJoined {
+                joined_at: Some(joined_at()),
+            })
+        })
+        .unwrap();
+        assert_eq!(json["joined_at"], JOINED_AT_SECS);
+
+        let seconds = json["joined_at"].as_i64().unwrap();
+        assert_eq!(
+            OffsetDateTime::from_unix_timestamp(seconds).unwrap(),
+            joined_at()
+        );
+    }
+
+    #[test]
+    fn defaults_to_rfc3339_outside_a_scope() {
+        let json = serde_json::to_value(MaybeJoined {
+            joined_at: Some(joined_at()),
+        })
+        .unwrap();
+        assert_eq!(json["joined_at"], "2023-10-01T12:30:45Z");
+
+        let json = with_format(TimestampFormat::Unix, || {
+            serde_json::to_value(MaybeJoined { joined_at: None })
+        })
+        .unwrap();
+        assert!(json["joined_at"].is_null());
+    }
+
+    #[tokio::test]
+    async fn concurrent_scopes_keep_their_own_format() {
+        let serialize_after_yield = |format| {
+            scope(format, async {
+                tokio::task::yield_now().await;
+                serde_json::to_value(MaybeJoined {
+                    joined_at: Some(joined_at()),
+                })
+                .unwrap()
+            })
+        };
+
+        let (unix, rfc3339) = tokio::join!(
+            serialize_after_yield(TimestampFormat::Unix),
+            serialize_after_yield(TimestampFormat::Rfc3339)
+        );
+        assert_eq!(unix["joined_at"], JOINED_AT_SECS);
+        assert_eq!(rfc3339["joined_at"], "2023-10-01T12:30:45Z");
+    }
+}
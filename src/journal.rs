@@ -0,0 +1,257 @@
+use std::path::PathBuf;
+
+use serde_json::json;
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::{AsyncWriteExt, BufWriter},
+    sync::broadcast::{error::RecvError, Receiver},
+    task::JoinHandle,
+};
+
+use crate::domain::{
+    events::EventEnvelope,
+    timestamp::{self, TimestampFormat},
+};
+
+/// Appends every published event to an NDJSON file, rotating it once it grows
+/// past `max_bytes`.
+pub struct EventJournal {
+    path: PathBuf,
+    max_bytes: u64,
+    timestamp_format: TimestampFormat,
+    writer: BufWriter<File>,
+    size: u64,
+}
+
+impl EventJournal {
+    pub async fn open(
+        path: PathBuf,
+        max_bytes: u64,
+        timestamp_format: TimestampFormat,
+    ) -> std::io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await?;
+        let size = file.metadata().await?.len();
+
+        Ok(Self {
+            path,
+            max_bytes,
+            timestamp_format,
+            writer: BufWriter::new(file),
+            size,
+        })
+    }
+
+    /// Runs until the event channel closes, then flushes what's buffered.
+    pub fn spawn(mut self, mut rx: Receiver<EventEnvelope>) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                match rx.recv().await {
+                    Ok(envelope) => {
+                        if let Err(e) = self.append(&envelope).await {
+                            tracing::error!("writing event to journal failed >>> {}", e);
+                        }
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        tracing::warn!(skipped, "event journal lagged, events were not journaled");
+                    }
+                    Err(RecvError::Closed) => break,
+                }
+            }
+
+            if let Err(e) = self.writer.flush().await {
+                tracing::error!("flushing event journal failed >>> {}", e);
+            }
+            tracing::info!("event journal closed >>> {}", self.path.display());
+        })
+    }
+
+    async fn append(&mut self, envelope: &EventEnvelope) -> std::io::Result<()> {
+        let mut line = timestamp::with_format(self.timestamp_format, || {
+            serde_json::to_vec(&json!({
+                "id": envelope.id,
+                "event": envelope.event,
+            }))
+        })?;
+        line.push(b'\n');
+
+        if self.size > 0 && self.size + line.len() as u64 > self.max_bytes {
+            self.rotate().await?;
+        }
+
+        self.writer.write_all(&line).await?;
+        self.writer.flush().await?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// Moves the current journal aside as `<path>.<unix nanos>` and starts a
+    /// fresh file at `path`.
+    async fn rotate(&mut self) -> std::io::Result<()> {
+        self.writer.flush().await?;
+
+        let suffix = time::OffsetDateTime::now_utc().unix_timestamp_nanos();
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(format!(".{}", suffix));
+        fs::rename(&self.path, &rotated).await?;
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        self.writer = BufWriter::new(file);
+        self.size = 0;
+        tracing::info!("rotated event journal >>> {:?}", rotated);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::events::{AppEvent, NewReferralEvent};
+    use serde_json::Value;
+    use tokio::sync::broadcast;
+
+    /// A fresh directory under the system temp dir, removed on drop.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            let path = std::env::temp_dir().join(format!("kpowa-journal-{}", uuid::Uuid::new_v4()));
+            std::fs::create_dir(&path).unwrap();
+            Self(path)
+        }
+
+        fn files(&self) -> Vec<PathBuf> {
+            let mut files: Vec<PathBuf> = std::fs::read_dir(&self.0)
+                .unwrap()
+                .map(|entry| entry.unwrap().path())
+                .collect();
+            files.sort();
+            files
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn referral(id: u64) -> EventEnvelope {
+        EventEnvelope {
+            id,
+            event: AppEvent::NewReferral(NewReferralEvent {
+                referrer: "alice".to_owned().into(),
+                referred_user: format!("user{id}").into(),
+            }),
+        }
+    }
+
+    fn journaled_ids(path: &std::path::Path) -> Vec<u64> {
+        std::fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| {
+                serde_json::from_str::<Value>(line).unwrap()["id"]
+                    .as_u64()
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn writes_one_json_object_per_line() {
+        let dir = TempDir::new();
+        let path = dir.0.join("events.ndjson");
+        let mut journal = EventJournal::open(path.clone(), 1024 * 1024, TimestampFormat::Rfc3339)
+            .await
+            .unwrap();
+        journal.append(&referral(1)).await.unwrap();
+        journal.append(&referral(2)).await.unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert!(contents.ends_with('\n'));
+        let lines: Vec<Value> = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["id"], 1);
+        assert_eq!(lines[0]["event"]["type"], "NewReferral");
+        assert_eq!(lines[0]["event"]["data"]["referrer"], "alice");
+        assert_eq!(lines[1]["id"], 2);
+        assert_eq!(lines[1]["event"]["data"]["referred_user"], "user2");
+    }
+
+    #[tokio::test]
+    async fn rotates_once_the_file_would_grow_past_max_bytes() {
+        let dir = TempDir::new();
+        let path = dir.0.join("events.ndjson");
+        let line_length = serde_json::to_vec(&json!({
+            "id": 1,
+            "event": referral(1).event,
+        }))
+        .unwrap()
+        .len() as u64
+            + 1;
+        // room for two lines but not three
+        let mut journal =
+            EventJournal::open(path.clone(), line_length * 2 + 1, TimestampFormat::Rfc3339)
+                .await
+                .unwrap();
+        for id in 1..=3 {
+            journal.append(&referral(id)).await.unwrap();
+        }
+
+        let files = dir.files();
+        assert_eq!(files.len(), 2);
+        let rotated = files.iter().find(|f| **f != path).unwrap();
+        assert_eq!(journaled_ids(rotated), [1, 2]);
+        assert_eq!(journaled_ids(&path), [3]);
+    }
+
+    #[tokio::test]
+    async fn picks_up_the_size_of_an_existing_journal() {
+        let dir = TempDir::new();
+        let path = dir.0.join("events.ndjson");
+        let mut journal = EventJournal::open(path.clone(), 1024, TimestampFormat::Rfc3339)
+            .await
+            .unwrap();
+        journal.append(&referral(1)).await.unwrap();
+        let size = std::fs::metadata(&path).unwrap().len();
+        drop(journal);
+
+        // reopened just too small for a second line
+        let mut journal = EventJournal::open(path.clone(), size + 1, TimestampFormat::Rfc3339)
+            .await
+            .unwrap();
+        journal.append(&referral(2)).await.unwrap();
+        assert_eq!(dir.files().len(), 2);
+        assert_eq!(journaled_ids(&path), [2]);
+    }
+
+    #[tokio::test]
+    async fn journals_published_events_until_the_channel_closes() {
+        let dir = TempDir::new();
+        let path = dir.0.join("events.ndjson");
+        let (tx, _rx) = broadcast::channel(16);
+        let journal = EventJournal::open(path.clone(), 1024 * 1024, TimestampFormat::Rfc3339)
+            .await
+            .unwrap()
+            .spawn(tx.subscribe());
+
+        for id in 1..=3 {
+            assert!(tx.send(referral(id)).is_ok());
+        }
+        drop(tx);
+        journal.await.unwrap();
+
+        assert_eq!(journaled_ids(&path), [1, 2, 3]);
+    }
+}
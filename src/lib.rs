@@ -1,6 +1,9 @@
 pub mod app;
 pub mod config;
 pub mod domain;
+pub mod journal;
+pub mod metrics;
+pub mod middleware;
 pub mod repository;
 pub mod routes;
 pub mod utils;
@@ -0,0 +1,152 @@
+//! Prometheus metrics, scraped from `GET /metrics`.
+
+use axum::{
+    extract::{MatchedPath, State},
+    http::Request,
+    middleware::Next,
+    response::Response,
+};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+};
+use std::{sync::Arc, time::Instant};
+
+use crate::{app::StreamStats, repository};
+
+pub struct Metrics {
+    registry: Registry,
+    authentications: IntCounterVec,
+    sse_subscribers: IntGauge,
+    request_duration: HistogramVec,
+}
+
+impl Metrics {
+    pub fn new(stream_stats: &StreamStats) -> anyhow::Result<Self> {
+        let registry = Registry::new();
+
+        let authentications = IntCounterVec::new(
+            Opts::new(
+                "authentications_total",
+                "Successful calls to /authenticate, by whether they logged in or registered",
+            ),
+            &["outcome"],
+        )?;
+        let sse_subscribers = IntGauge::new("sse_subscribers", "Open SSE streams")?;
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "Time to produce a response, by route",
+            ),
+            &["method", "route", "status"],
+        )?;
+
+        registry.register(Box::new(authentications.clone()))?;
+        registry.register(Box::new(sse_subscribers.clone()))?;
+        registry.register(Box::new(request_duration.clone()))?;
+        registry.register(Box::new(repository::server_error_counter().clone()))?;
+        stream_stats.register(&registry)?;
+
+        Ok(Self {
+            registry,
+            authentications,
+            sse_subscribers,
+            request_duration,
+        })
+    }
+
+    pub fn record_login(&self) {
+        self.authentications.with_label_values(&["login"]).inc();
+    }
+
+    pub fn record_registration(&self) {
+        self.authentications.with_label_values(&["register"]).inc();
+    }
+
+    /// Counts an SSE subscriber for as long as the returned guard is alive.
+    pub fn track_subscriber(&self) -> SubscriberGuard {
+        self.sse_subscribers.inc();
+        SubscriberGuard(self.sse_subscribers.clone())
+    }
+
+    pub fn render(&self) -> anyhow::Result<String> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8(buffer)?)
+    }
+}
+
+pub struct SubscriberGuard(IntGauge);
+
+impl Drop for SubscriberGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+/// Records how long each request took. Labels use the route pattern rather
+/// than the raw path so ids in urls don't blow up the label set.
+pub async fn track_latency<B>(
+    State(metrics): State<Arc<Metrics>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|p| p.as_str().to_owned())
+        .unwrap_or_else(|| "unmatched".to_owned());
+    let method = request.method().to_string();
+    let started = Instant::now();
+
+    let response = next.run(request).await;
+
+    metrics
+        .request_duration
+        .with_label_values(&[&method, &route, response.status().as_str()])
+        .observe(started.elapsed().as_secs_f64());
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{app::TestApp, config::test_config};
+
+    #[test]
+    fn stream_problems_are_exported() {
+        let stream_stats = StreamStats::new().unwrap();
+        let metrics = Metrics::new(&stream_stats).unwrap();
+        stream_stats.record_serialization_failure();
+        stream_stats.record_lagged_events(3);
+
+        let rendered = metrics.render().unwrap();
+        assert!(rendered.contains("stream_serialization_failures_total 1"));
+        assert!(rendered.contains("stream_lagged_events_total 3"));
+    }
+
+    async fn scrape(app: &TestApp) -> String {
+        let request = Request::builder()
+            .uri("/metrics")
+            .body(axum::body::Body::empty())
+            .unwrap();
+        let body = hyper::body::to_bytes(app.send(request).await.into_body())
+            .await
+            .unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[sqlx::test]
+    async fn authenticating_is_counted(pool: sqlx::PgPool) {
+        let app = TestApp::new(test_config(), pool);
+        app.authenticate("alice", None).await;
+        app.authenticate("alice", None).await;
+        app.authenticate("alice", None).await;
+
+        let rendered = scrape(&app).await;
+        assert!(rendered.contains(r#"authentications_total{outcome="register"} 1"#));
+        assert!(rendered.contains(r#"authentications_total{outcome="login"} 2"#));
+        assert!(rendered.contains(
+            r#"http_request_duration_seconds_count{method="POST",route="/authenticate",status="200"} 3"#
+        ));
+    }
+}
@@ -0,0 +1,12 @@
+use axum::{extract::State, http::Request, middleware::Next, response::Response};
+use std::time::Duration;
+
+pub async fn inject_latency<B>(
+    State(latency): State<Duration>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let response = next.run(request).await;
+    tokio::time::sleep(latency).await;
+    response
+}
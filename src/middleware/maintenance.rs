@@ -0,0 +1,75 @@
+use crate::app::AppState;
+use axum::{
+    extract::State,
+    http::Request,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::sync::Arc;
+
+/// Turns a write away with a 503 while maintenance mode is on. Layered on
+/// every route that only writes, `/authenticate` checks for itself since
+/// logging in stays open.
+pub async fn reject_in_maintenance<B>(
+    State(state): State<Arc<AppState>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if let Err(e) = state.ensure_writable() {
+        tracing::warn!(
+            "write refused in maintenance mode >>> {}",
+            request.uri().path()
+        );
+        return e.into_response();
+    }
+
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{app::TestApp, config::test_config};
+    use axum::http::{header, Method, StatusCode};
+    use serde_json::json;
+    use sqlx::PgPool;
+
+    #[sqlx::test]
+    async fn blocks_writes_but_not_reads_or_logins(pool: PgPool) {
+        let app = TestApp::new(test_config(), pool);
+        let token = app.authenticate("alice", None).await["token"]
+            .as_str()
+            .unwrap()
+            .to_owned();
+        app.state.set_maintenance(true);
+
+        let request = axum::http::Request::builder()
+            .method(Method::POST)
+            .uri("/authenticate")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(json!({ "username": "bob" }).to_string().into())
+            .unwrap();
+        let response = app.send(request).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "300");
+
+        app.authenticate("alice", None).await;
+        let (status, body) = app.call(Method::GET, "/users/me", Some(&token), None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["username"], "alice");
+
+        for (method, uri) in [
+            (Method::DELETE, "/users/me"),
+            (Method::POST, "/users/me/deactivate"),
+        ] {
+            let (status, body) = app.call(method, uri, Some(&token), None).await;
+            assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+            assert_eq!(body["code"], "MAINTENANCE_MODE");
+        }
+
+        app.state.set_maintenance(false);
+        let (status, _) = app
+            .call(Method::POST, "/users/me/deactivate", Some(&token), None)
+            .await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+    }
+}
@@ -0,0 +1,6 @@
+pub mod chaos;
+pub mod maintenance;
+pub mod rate_limit;
+pub mod request_id;
+pub mod request_log;
+pub mod timestamp_format;
@@ -0,0 +1,78 @@
+use crate::{
+    domain::errors::ApiError,
+    utils::{client_ip::ClientIp, rate_limit::IpWindowCounter},
+};
+use axum::{
+    extract::State,
+    http::Request,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::sync::Arc;
+
+pub async fn limit_by_ip<B>(
+    State(counter): State<Arc<IpWindowCounter>>,
+    ClientIp(ip): ClientIp,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if let Err(retry_after) = counter.try_acquire(ip) {
+        tracing::warn!("rate limit exceeded >>> {} {}", ip, request.uri().path());
+        // round up so clients never retry a moment too early
+        let retry_after = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+        return ApiError::RateLimited(retry_after).into_response();
+    }
+
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        body::Body,
+        extract::ConnectInfo,
+        http::{header, StatusCode},
+        middleware,
+        routing::get,
+        Router,
+    };
+    use std::{net::SocketAddr, time::Duration};
+    use tower::ServiceExt;
+
+    fn request_from(ip: [u8; 4]) -> Request<Body> {
+        let mut request = Request::builder().uri("/").body(Body::empty()).unwrap();
+        request
+            .extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from((ip, 4000))));
+        request
+    }
+
+    #[tokio::test]
+    async fn rejects_the_request_over_the_limit_but_not_other_clients() {
+        let counter = Arc::new(IpWindowCounter::new(2, Duration::from_secs(60)));
+        let app = Router::new()
+            .route("/", get(|| async { "ok" }))
+            .route_layer(middleware::from_fn_with_state(counter, limit_by_ip));
+
+        for _ in 0..2 {
+            let response = app
+                .clone()
+                .oneshot(request_from([10, 0, 0, 1]))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+
+        let response = app
+            .clone()
+            .oneshot(request_from([10, 0, 0, 1]))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "60");
+
+        let response = app.oneshot(request_from([10, 0, 0, 2])).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
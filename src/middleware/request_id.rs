@@ -0,0 +1,80 @@
+use axum::{
+    http::{HeaderName, HeaderValue, Request},
+    middleware::Next,
+    response::Response,
+};
+use tracing::Instrument;
+use uuid::Uuid;
+
+pub static X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Tags everything logged while handling a request with a request id, taken
+/// from `X-Request-Id` when the caller sent a usable one, and echoes it back.
+pub async fn propagate_request_id<B>(request: Request<B>, next: Next<B>) -> Response {
+    let request_id = request
+        .headers()
+        .get(&X_REQUEST_ID)
+        .and_then(|v| v.to_str().ok())
+        .map(str::trim)
+        .filter(|v| !v.is_empty() && v.len() <= 128)
+        .map(str::to_owned)
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+
+    let span = tracing::info_span!(
+        "request",
+        request_id = %request_id,
+        method = %request.method(),
+        path = %request.uri().path(),
+    );
+    let mut response = next.run(request).instrument(span).await;
+
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert(X_REQUEST_ID.clone(), value);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, middleware, routing::get, Router};
+    use tower::ServiceExt;
+
+    fn app() -> Router {
+        Router::new()
+            .route("/", get(|| async { "ok" }))
+            .layer(middleware::from_fn(propagate_request_id))
+    }
+
+    fn request(request_id: Option<&str>) -> Request<Body> {
+        let mut request = Request::builder().uri("/");
+        if let Some(request_id) = request_id {
+            request = request.header(&X_REQUEST_ID, request_id);
+        }
+        request.body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn echoes_the_callers_request_id() {
+        let response = app().oneshot(request(Some("abc-123"))).await.unwrap();
+        assert_eq!(response.headers()[&X_REQUEST_ID], "abc-123");
+    }
+
+    #[tokio::test]
+    async fn generates_a_request_id_when_there_is_no_usable_one() {
+        let too_long = "a".repeat(129);
+        for request_id in [None, Some(""), Some("   "), Some(too_long.as_str())] {
+            let response = app().oneshot(request(request_id)).await.unwrap();
+            let generated = response.headers()[&X_REQUEST_ID].to_str().unwrap();
+            assert!(Uuid::parse_str(generated).is_ok(), "{request_id:?}");
+        }
+
+        // a fresh one every time
+        let first = app().oneshot(request(None)).await.unwrap();
+        let second = app().oneshot(request(None)).await.unwrap();
+        assert_ne!(
+            first.headers()[&X_REQUEST_ID],
+            second.headers()[&X_REQUEST_ID]
+        );
+    }
+}
@@ -0,0 +1,125 @@
+use crate::config::LoggingConfig;
+use axum::{
+    extract::{MatchedPath, State},
+    http::Request,
+    middleware::Next,
+    response::Response,
+};
+use std::{sync::Arc, time::Instant};
+
+/// Logs each request with its status and duration. Failed requests are always
+/// logged, successful ones only at the configured sample rate for the route.
+pub async fn log_requests<B>(
+    State(config): State<Arc<LoggingConfig>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|p| p.as_str().to_owned());
+    let method = request.method().clone();
+    let path = request.uri().path().to_owned();
+    let started = Instant::now();
+
+    let response = next.run(request).await;
+
+    let status = response.status();
+    let elapsed = started.elapsed();
+    if status.is_server_error() {
+        tracing::error!("{} {} >>> {} in {:?}", method, path, status, elapsed);
+    } else if status.is_client_error() {
+        tracing::warn!("{} {} >>> {} in {:?}", method, path, status, elapsed);
+    } else {
+        let rate = config.sample_rate(route.as_deref().unwrap_or(&path));
+        if rate > 0.0 && rand::random::<f64>() < rate {
+            tracing::info!("{} {} >>> {} in {:?}", method, path, status, elapsed);
+        }
+    }
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, http::StatusCode, middleware, routing::get, Router};
+    use std::{collections::HashMap, sync::Mutex};
+    use tower::ServiceExt;
+    use tracing_subscriber::fmt::MakeWriter;
+
+    /// Collects everything logged while it's the default subscriber.
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Captured {
+        fn lines(&self) -> Vec<String> {
+            String::from_utf8(self.0.lock().unwrap().clone())
+                .unwrap()
+                .lines()
+                .map(str::to_owned)
+                .collect()
+        }
+    }
+
+    impl std::io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'a> MakeWriter<'a> for Captured {
+        type Writer = Self;
+
+        fn make_writer(&'a self) -> Self::Writer {
+            self.clone()
+        }
+    }
+
+    /// Sends ten successful requests and one failing one, returning what was
+    /// logged.
+    async fn log_with(success_sample_rate: f64) -> Vec<String> {
+        let captured = Captured::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(captured.clone())
+            .with_ansi(false)
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let config = Arc::new(LoggingConfig {
+            success_sample_rate,
+            route_sample_rates: HashMap::new(),
+        });
+        let app = Router::new()
+            .route("/ok", get(|| async { "ok" }))
+            .route("/fail", get(|| async { StatusCode::INTERNAL_SERVER_ERROR }))
+            .layer(middleware::from_fn_with_state(config, log_requests));
+
+        for uri in std::iter::repeat_n("/ok", 10).chain(["/fail"]) {
+            let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+            app.clone().oneshot(request).await.unwrap();
+        }
+
+        captured.lines()
+    }
+
+    #[tokio::test]
+    async fn a_zero_rate_logs_only_failures() {
+        let lines = log_with(0.0).await;
+        assert_eq!(lines.len(), 1, "{lines:?}");
+        assert!(lines[0].contains("GET /fail >>> 500"));
+    }
+
+    #[tokio::test]
+    async fn a_full_rate_logs_every_request() {
+        let lines = log_with(1.0).await;
+        assert_eq!(lines.len(), 11, "{lines:?}");
+        let successes = lines.iter().filter(|l| l.contains("GET /ok >>> 200"));
+        assert_eq!(successes.count(), 10);
+    }
+}
@@ -0,0 +1,42 @@
+use crate::domain::timestamp::{self, TimestampFormat};
+use axum::{extract::State, http::Request, middleware::Next, response::Response};
+
+/// Serializes the timestamps in a response in the configured format.
+pub async fn format_timestamps<B>(
+    State(format): State<TimestampFormat>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    timestamp::scope(format, next.run(request)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{app::TestApp, config::test_config};
+    use axum::http::{Method, StatusCode};
+    use sqlx::PgPool;
+
+    #[sqlx::test]
+    async fn responses_follow_the_configured_format(pool: PgPool) {
+        for (format, is_expected) in [
+            (
+                TimestampFormat::Rfc3339,
+                serde_json::Value::is_string as fn(&_) -> bool,
+            ),
+            (TimestampFormat::Unix, serde_json::Value::is_i64),
+        ] {
+            let mut config = test_config();
+            config.application.timestamp_format = format;
+            let app = TestApp::new(config, pool.clone());
+            let token = app.authenticate("alice", None).await["token"]
+                .as_str()
+                .unwrap()
+                .to_owned();
+
+            let (status, body) = app.call(Method::GET, "/users/me", Some(&token), None).await;
+            assert_eq!(status, StatusCode::OK);
+            assert!(is_expected(&body["joinedAt"]), "{:?} >>> {}", format, body);
+        }
+    }
+}
@@ -1,132 +1,1112 @@
 use crate::domain::{
     errors::DatabaseError,
-    fields::{InviteCode, User, Username},
-    model::DbUser,
+    fields::{InviteCode, Role, User, Username},
+    model::{DbCohortRank, DbLoginEvent, DbRankedUser, DbTreeUser, DbUser},
 };
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use prometheus::IntCounter;
+use serde::Deserialize;
 use sqlx::{PgPool, Postgres, QueryBuilder, Row};
+use std::{
+    future::Future,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        OnceLock,
+    },
+};
+use time::OffsetDateTime;
 use uuid::Uuid;
 
+static RETRY_ON_CONNECTION_ERROR: AtomicBool = AtomicBool::new(true);
+
+pub fn set_retry_on_connection_error(enabled: bool) {
+    RETRY_ON_CONNECTION_ERROR.store(enabled, Ordering::Relaxed);
+}
+
+static SERVER_ERRORS: OnceLock<IntCounter> = OnceLock::new();
+
+/// Counts queries that failed for reasons other than a missing row or a
+/// conflict, i.e. `ConnectionError` and `QueryError`.
+pub fn server_error_counter() -> &'static IntCounter {
+    SERVER_ERRORS.get_or_init(|| {
+        IntCounter::new(
+            "db_server_errors_total",
+            "Database queries that failed with a server error",
+        )
+        .expect("valid metric")
+    })
+}
+
+/// Maps a failed query onto the `DatabaseError` callers can act on.
+fn database_error(e: &sqlx::Error) -> DatabaseError {
+    let error = if matches!(e, sqlx::Error::RowNotFound) {
+        DatabaseError::NotFound
+    } else if is_unique_violation(e) {
+        DatabaseError::Conflict
+    } else if is_connection_error(e) {
+        DatabaseError::ConnectionError
+    } else {
+        DatabaseError::QueryError
+    };
+
+    if matches!(
+        error,
+        DatabaseError::ConnectionError | DatabaseError::QueryError
+    ) {
+        server_error_counter().inc();
+    }
+    error
+}
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderBy {
+    #[default]
+    CreatedOn,
+    Referrals,
+}
+
+impl OrderBy {
+    fn as_sql(&self) -> &'static str {
+        match self {
+            Self::CreatedOn => " order by created_on desc, uid desc ",
+            Self::Referrals => " order by referrals desc, created_on asc ",
+        }
+    }
+}
+
+/// Where a page ends when paging by `created_on`, handed to clients as an
+/// opaque string. Unlike an offset it stays put when users sign up mid-paging.
+#[derive(Clone, Copy)]
+pub struct UserCursor {
+    created_on: OffsetDateTime,
+    uid: Uuid,
+}
+
+impl UserCursor {
+    pub fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(format!(
+            "{}:{}",
+            self.created_on.unix_timestamp_nanos(),
+            self.uid
+        ))
+    }
+
+    pub fn decode(value: &str) -> Option<Self> {
+        let raw = String::from_utf8(URL_SAFE_NO_PAD.decode(value).ok()?).ok()?;
+        let (nanos, uid) = raw.split_once(':')?;
+        Some(Self {
+            created_on: OffsetDateTime::from_unix_timestamp_nanos(nanos.parse().ok()?).ok()?,
+            uid: uid.parse().ok()?,
+        })
+    }
+}
+
+impl From<&DbUser> for UserCursor {
+    fn from(value: &DbUser) -> Self {
+        Self {
+            created_on: value.created_on,
+            uid: value.uid,
+        }
+    }
+}
+
 pub struct FetchUserQuery {
     pub username: Option<String>,
-    pub auth_user: String,
+    pub invite_code: Option<InviteCode>,
+    /// Only users referred by this username.
+    pub referred_by: Option<String>,
+    /// Leave this user out, typically the caller.
+    pub exclude_user: Option<String>,
+    /// Only users with this role.
+    pub role: Option<Role>,
     pub skip: i64,
     pub limit: i64,
+    pub with_total: bool,
+    pub order_by: OrderBy,
+    /// Continue after this row instead of skipping `skip` rows. Only
+    /// meaningful with `OrderBy::CreatedOn`.
+    pub cursor: Option<UserCursor>,
+    pub include_deactivated: bool,
 }
 
+/// Usernames are case-insensitive, so this finds the account whatever casing
+/// it was registered with. Deactivated accounts are returned too, so callers
+/// can tell them apart from usernames that are free.
 pub async fn get_user_by_username(
     pool: &PgPool,
     username: &Username,
 ) -> Result<Option<User>, DatabaseError> {
-    let user = sqlx::query_as!(
-        DbUser,
-        "select a.*, (select count(referred_by) from users as b where b.referred_by=a.username) as referrals from users as a where username = $1",
-        username.inner()
-    )
-    .fetch_optional(pool)
+    let user = with_reconnect(|| {
+        sqlx::query_as!(
+            DbUser,
+            "select a.*, (select count(referred_by) from users as b where b.referred_by=a.username and b.deactivated_at is null) as referrals from users as a where lower(username) = lower($1)",
+            username.inner()
+        )
+        .fetch_optional(pool)
+    })
     .await
     .map_err(|e| {
          tracing::error!("get user by username failed >>> {}",e);
-         DatabaseError::ServerError
+         database_error(&e)
     })?;
 
     Ok(user.map(|u| u.into()))
 }
 
+/// Looks up the owner of `invite_code`, ignoring case.
 pub async fn get_user_by_invite_code(
     pool: &PgPool,
     invite_code: &InviteCode,
 ) -> Result<Option<User>, DatabaseError> {
-    let user = sqlx::query_as!(
-        DbUser,
-        "select a.*, (select count(referred_by) from users as b where b.referred_by=a.username) as referrals from users as a where invite_code = $1",
-        invite_code.inner()
-    )
-    .fetch_optional(pool)
+    let user = with_reconnect(|| {
+        sqlx::query_as!(
+            DbUser,
+            "select a.*, (select count(referred_by) from users as b where b.referred_by=a.username and b.deactivated_at is null) as referrals from users as a where lower(invite_code) = lower($1)",
+            invite_code.inner()
+        )
+        .fetch_optional(pool)
+    })
     .await
     .map_err(|e| {
         tracing::error!("get user by invite failed >>> {}",e);
-        DatabaseError::ServerError
+        database_error(&e)
     })?;
 
     Ok(user.map(|u| u.into()))
 }
 
+/// Looks up many active users in one query, ignoring case like
+/// `get_user_by_username`. Names with no account, or a deactivated one, are
+/// simply missing from the result.
+pub async fn get_users_by_usernames(
+    pool: &PgPool,
+    usernames: &[String],
+) -> Result<Vec<User>, DatabaseError> {
+    let users = with_reconnect(|| {
+        sqlx::query_as!(
+            DbUser,
+            "select a.*, (select count(referred_by) from users as b where b.referred_by=a.username and b.deactivated_at is null) as referrals from users as a where lower(username) = any(select lower(u) from unnest($1::text[]) as u) and a.deactivated_at is null",
+            usernames
+        )
+        .fetch_all(pool)
+    })
+    .await
+    .map_err(|e| {
+        tracing::error!("get users by usernames failed >>> {}", e);
+        database_error(&e)
+    })?;
+
+    Ok(users.into_iter().map(|u| u.into()).collect())
+}
+
 pub async fn create_new_user(
     pool: &PgPool,
     username: &Username,
     invite_code: &InviteCode,
     referred_by: Option<Username>,
 ) -> Result<(), DatabaseError> {
-    sqlx::query!(
-        "insert into users (uid, username, invite_code, referred_by) values ($1, $2, $3, $4)",
-        Uuid::new_v4(),
-        username.inner(),
-        invite_code.inner(),
-        referred_by.map(|r| r.inner())
-    )
-    .execute(pool)
+    // A retried insert that had in fact committed surfaces as a unique
+    // violation, which callers already treat as a concurrent registration.
+    // the only unique index on invite codes, see the case-insensitive
+    // invite code migration
+    const INVITE_CODE_KEY: &str = "users_invite_code_lower_key";
+    let uid = Uuid::new_v4();
+    with_reconnect(|| {
+        sqlx::query!(
+            "insert into users (uid, username, invite_code, referred_by) values ($1, $2, $3, $4)",
+            uid,
+            username.inner(),
+            invite_code.inner(),
+            referred_by.as_ref().map(|r| r.inner())
+        )
+        .execute(pool)
+    })
     .await
     .map_err(|e| {
+        if violated_constraint(&e) == Some(INVITE_CODE_KEY) {
+            tracing::info!(
+                "creating user failed >>> invite code {} is taken",
+                invite_code.inner()
+            );
+            return DatabaseError::InviteCodeTaken;
+        }
         tracing::error!("creating user failed >>> {}", e);
-        DatabaseError::ServerError
+        database_error(&e)
     })?;
 
     Ok(())
 }
 
-pub async fn fetch_users(
+/// Marks `username` deactivated. Their row, and so the referrals they made,
+/// stay in place, but they stop counting towards their referrer's
+/// referrals.
+pub async fn deactivate_user(pool: &PgPool, username: &Username) -> Result<(), DatabaseError> {
+    with_reconnect(|| {
+        sqlx::query!(
+            "update users set deactivated_at = now() where lower(username) = lower($1) and deactivated_at is null",
+            username.inner()
+        )
+        .execute(pool)
+    })
+    .await
+    .map_err(|e| {
+        tracing::error!("deactivating user failed >>> {}", e);
+        database_error(&e)
+    })?;
+
+    Ok(())
+}
+
+pub async fn record_login_event(
     pool: &PgPool,
-    query: FetchUserQuery,
-) -> Result<(Vec<User>, i64), DatabaseError> {
-    tracing::info!("limit >>> {} offset >>> {}", query.limit, query.skip);
-    let mut select_query = QueryBuilder::new("select a.*, (select count(referred_by) from users as b where b.referred_by=a.username) as referrals from users as a ");
-    let builder = append_search_param_to_query(&mut select_query, &query, false, false);
+    username: &Username,
+    ip_address: IpAddr,
+) -> Result<(), DatabaseError> {
+    with_reconnect(|| {
+        sqlx::query("insert into login_events (username, ip_address) values ($1, $2)")
+            .bind(username.inner())
+            .bind(ip_address.to_string())
+            .execute(pool)
+    })
+    .await
+    .map_err(|e| {
+        tracing::error!("recording login failed >>> {}", e);
+        database_error(&e)
+    })?;
+
+    Ok(())
+}
+
+/// Records which of `milestones` `username` has reached with `referrals`,
+/// returning only the ones not recorded before. The primary key makes this
+/// safe to race: a milestone is only ever returned to one caller.
+pub async fn record_referral_milestones(
+    pool: &PgPool,
+    username: &Username,
+    referrals: i64,
+    milestones: &[i64],
+) -> Result<Vec<i64>, DatabaseError> {
+    with_reconnect(|| {
+        sqlx::query_scalar::<_, i64>(
+            "insert into referral_milestones (username, milestone) select $1, m from unnest($2::bigint[]) as m where m <= $3 on conflict do nothing returning milestone",
+        )
+        .bind(username.inner())
+        .bind(milestones)
+        .bind(referrals)
+        .fetch_all(pool)
+    })
+    .await
+    .map_err(|e| {
+        tracing::error!("recording referral milestones failed >>> {}", e);
+        database_error(&e)
+    })
+}
+
+/// Records every configured milestone users are already past without
+/// announcing it, so adding a milestone, or turning them on for an existing
+/// user base, doesn't fire a burst of stale ones on the next referral.
+/// Returns how many were recorded.
+pub async fn backfill_referral_milestones(
+    pool: &PgPool,
+    milestones: &[i64],
+) -> Result<u64, DatabaseError> {
+    let result = with_reconnect(|| {
+        sqlx::query(
+            "insert into referral_milestones (username, milestone) select a.username, m from users as a cross join unnest($1::bigint[]) as m where (select count(*) from users as b where b.referred_by = a.username and b.deactivated_at is null) >= m on conflict do nothing",
+        )
+        .bind(milestones)
+        .execute(pool)
+    })
+    .await
+    .map_err(|e| {
+        tracing::error!("backfilling referral milestones failed >>> {}", e);
+        database_error(&e)
+    })?;
 
-    let mut count_query = QueryBuilder::new("select count(*) from users as count ");
-    let count_builder = append_search_param_to_query(&mut count_query, &query, true, true);
+    Ok(result.rows_affected())
+}
 
-    let users = builder
-        .build_query_as::<DbUser>()
+/// `username`'s logins, most recent first, with their total count.
+pub async fn fetch_login_events(
+    pool: &PgPool,
+    username: &Username,
+    skip: i64,
+    limit: i64,
+) -> Result<(Vec<DbLoginEvent>, i64), DatabaseError> {
+    let events = with_reconnect(|| {
+        sqlx::query_as::<_, DbLoginEvent>(
+            "select ip_address, logged_in_at from login_events where username = $1 order by logged_in_at desc, id desc limit $2 offset $3",
+        )
+        .bind(username.inner())
+        .bind(limit)
+        .bind(skip)
         .fetch_all(pool)
+    })
+    .await
+    .map_err(|e| {
+        tracing::error!("fetch login events failed >>> {}", e);
+        database_error(&e)
+    })?;
+
+    let count = with_reconnect(|| {
+        sqlx::query_scalar::<_, i64>("select count(*) from login_events where username = $1")
+            .bind(username.inner())
+            .fetch_one(pool)
+    })
+    .await
+    .map_err(|e| {
+        tracing::error!("count login events failed >>> {}", e);
+        database_error(&e)
+    })?;
+
+    Ok((events, count))
+}
+
+pub async fn count_users(pool: &PgPool) -> Result<i64, DatabaseError> {
+    with_reconnect(|| sqlx::query_scalar::<_, i64>("select count(*) from users").fetch_one(pool))
         .await
         .map_err(|e| {
-            tracing::error!("getting list of user failed >>> {}", e);
-            DatabaseError::ServerError
-        })?;
+            tracing::error!("count users failed >>> {}", e);
+            database_error(&e)
+        })
+}
+
+/// Deletes `username`. Anyone they referred is handed up to their own
+/// referrer (or left unreferred) in the same transaction, so no
+/// `referred_by` is left pointing at a missing user.
+pub async fn delete_user(pool: &PgPool, username: &Username) -> Result<(), DatabaseError> {
+    with_reconnect(|| async move {
+        let mut tx = pool.begin().await?;
+
+        sqlx::query!(
+            "update users set referred_by = (select referred_by from users where lower(username) = lower($1)) where lower(referred_by) = lower($1)",
+            username.inner()
+        )
+        .execute(&mut tx)
+        .await?;
 
-    let count = count_builder.build().fetch_one(pool).await.map_err(|e| {
+        sqlx::query!("delete from users where lower(username) = lower($1)", username.inner())
+            .execute(&mut tx)
+            .await?;
+
+        tx.commit().await
+    })
+    .await
+    .map_err(|e| {
+        tracing::error!("deleting user failed >>> {}", e);
+        database_error(&e)
+    })
+}
+
+pub async fn fetch_users(
+    pool: &PgPool,
+    query: FetchUserQuery,
+) -> Result<(Vec<(UserCursor, User)>, Option<i64>), DatabaseError> {
+    tracing::info!("limit >>> {} offset >>> {}", query.limit, query.skip);
+    let query = &query;
+    let users = with_reconnect(|| async move {
+        let mut select_query = QueryBuilder::new("select a.*, (select count(referred_by) from users as b where b.referred_by=a.username and b.deactivated_at is null) as referrals from users as a ");
+        append_search_param_to_query(&mut select_query, query, false, false)
+            .build_query_as::<DbUser>()
+            .fetch_all(pool)
+            .await
+    })
+    .await
+    .map_err(|e| {
+        tracing::error!("getting list of user failed >>> {}", e);
+        database_error(&e)
+    })?;
+
+    let users: Vec<(UserCursor, User)> = users
+        .into_iter()
+        .map(|u| (UserCursor::from(&u), u.into()))
+        .collect();
+
+    if !query.with_total {
+        return Ok((users, None));
+    }
+
+    let count = with_reconnect(|| async move {
+        let mut count_query = QueryBuilder::new("select count(*) from users as count ");
+        append_search_param_to_query(&mut count_query, query, true, true)
+            .build()
+            .fetch_one(pool)
+            .await
+    })
+    .await
+    .map_err(|e| {
+        tracing::error!("fetch total user count failed >>> {}", e);
+        database_error(&e)
+    })?;
+
+    Ok((users, Some(count.get("count"))))
+}
+
+/// Active users ranked by referral count. Users with the same count share a
+/// rank.
+pub async fn fetch_leaderboard(
+    pool: &PgPool,
+    skip: i64,
+    limit: i64,
+) -> Result<(Vec<(i64, User)>, i64), DatabaseError> {
+    let users = with_reconnect(|| async move {
+        let mut select_query = QueryBuilder::new("select r.*, rank() over (order by r.referrals desc) as rank from (select a.*, (select count(referred_by) from users as b where b.referred_by=a.username and b.deactivated_at is null) as referrals from users as a where a.deactivated_at is null) as r ");
+        select_query.push(OrderBy::Referrals.as_sql());
+        select_query.push(" limit ");
+        select_query.push_bind(limit);
+        select_query.push(" offset ");
+        select_query.push_bind(skip);
+
+        select_query
+            .build_query_as::<DbRankedUser>()
+            .fetch_all(pool)
+            .await
+    })
+    .await
+    .map_err(|e| {
+        tracing::error!("getting leaderboard failed >>> {}", e);
+        database_error(&e)
+    })?;
+
+    let count = with_reconnect(|| async move {
+        QueryBuilder::<Postgres>::new(
+            "select count(*) from users as count where deactivated_at is null",
+        )
+        .build()
+        .fetch_one(pool)
+        .await
+    })
+    .await
+    .map_err(|e| {
         tracing::error!("fetch total user count failed >>> {}", e);
-        DatabaseError::ServerError
+        database_error(&e)
     })?;
 
-    let users: Vec<User> = users.into_iter().map(|u| u.into()).collect();
+    let users = users.into_iter().map(|u| (u.rank, u.user.into())).collect();
     Ok((users, count.get("count")))
 }
 
+/// Active users directly referred by `username`, newest first, with their
+/// total count.
+pub async fn fetch_referrals(
+    pool: &PgPool,
+    username: &Username,
+    skip: i64,
+    limit: i64,
+) -> Result<(Vec<User>, i64), DatabaseError> {
+    let users = with_reconnect(|| {
+        sqlx::query_as::<_, DbUser>(
+            "select a.*, (select count(referred_by) from users as b where b.referred_by=a.username and b.deactivated_at is null) as referrals from users as a where lower(a.referred_by) = lower($1) and a.deactivated_at is null order by a.created_on desc, a.uid desc limit $2 offset $3",
+        )
+        .bind(username.inner())
+        .bind(limit)
+        .bind(skip)
+        .fetch_all(pool)
+    })
+    .await
+    .map_err(|e| {
+        tracing::error!("fetch referrals failed >>> {}", e);
+        database_error(&e)
+    })?;
+
+    let count = with_reconnect(|| {
+        sqlx::query_scalar::<_, i64>(
+            "select count(*) from users where lower(referred_by) = lower($1) and deactivated_at is null",
+        )
+        .bind(username.inner())
+        .fetch_one(pool)
+    })
+    .await
+    .map_err(|e| {
+        tracing::error!("count referrals failed >>> {}", e);
+        database_error(&e)
+    })?;
+
+    Ok((users.into_iter().map(|u| u.into()).collect(), count))
+}
+
+/// Users referred by `username` directly or through others, down to `depth`
+/// levels, each with its level (1 for direct referrals). Deactivated users
+/// are included so callers can decide how to show the branches below them.
+/// Ordered by level, newest first within a level.
+pub async fn fetch_referral_tree(
+    pool: &PgPool,
+    username: &Username,
+    depth: i32,
+) -> Result<Vec<(i32, User)>, DatabaseError> {
+    // `path` keeps each branch from revisiting a user, so bad data with a
+    // referral cycle can't loop until the depth cap
+    let users = with_reconnect(|| {
+        sqlx::query_as::<_, DbTreeUser>(
+            "with recursive tree as (select a.uid, array[a.username::text] as path, 1 as depth from users as a where lower(a.referred_by) = lower($1) and lower(a.username) <> lower($1) union all select c.uid, t.path || c.username::text, t.depth + 1 from users as c join users as p on c.referred_by = p.username join tree as t on p.uid = t.uid where t.depth < $2 and lower(c.username) <> lower($1) and c.username <> all(t.path)) select a.*, (select count(referred_by) from users as b where b.referred_by=a.username and b.deactivated_at is null) as referrals, t.depth from tree as t join users as a on a.uid = t.uid order by t.depth, a.created_on desc, a.uid desc",
+        )
+        .bind(username.inner())
+        .bind(depth)
+        .fetch_all(pool)
+    })
+    .await
+    .map_err(|e| {
+        tracing::error!("fetch referral tree failed >>> {}", e);
+        database_error(&e)
+    })?;
+
+    Ok(users
+        .into_iter()
+        .map(|u| (u.depth, u.user.into()))
+        .collect())
+}
+
+/// Ranks `username` by referral count among active users who joined in the
+/// same ISO week.
+pub async fn get_cohort_rank(
+    pool: &PgPool,
+    username: &Username,
+) -> Result<Option<DbCohortRank>, DatabaseError> {
+    let rank = with_reconnect(|| {
+        sqlx::query_as::<_, DbCohortRank>(
+            "select c.rank, c.cohort_size, c.cohort_start from (select a.username, date_trunc('week', a.created_on) as cohort_start, rank() over (partition by date_trunc('week', a.created_on) order by (select count(referred_by) from users as b where b.referred_by=a.username and b.deactivated_at is null) desc) as rank, count(*) over (partition by date_trunc('week', a.created_on)) as cohort_size from users as a where a.deactivated_at is null and date_trunc('week', a.created_on) = (select date_trunc('week', created_on) from users where lower(username) = lower($1))) as c where lower(c.username) = lower($1)",
+        )
+        .bind(username.inner())
+        .fetch_optional(pool)
+    })
+    .await
+    .map_err(|e| {
+        tracing::error!("get cohort rank failed >>> {}", e);
+        database_error(&e)
+    })?;
+
+    Ok(rank)
+}
+
+/// Runs `op`, and if it failed because the connection itself broke (as
+/// opposed to the query being rejected), runs it once more. The pool tests
+/// connections before handing them out, so the retry gets a fresh one.
+async fn with_reconnect<T, F, Fut>(mut op: F) -> Result<T, sqlx::Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, sqlx::Error>>,
+{
+    match op().await {
+        Err(e) if RETRY_ON_CONNECTION_ERROR.load(Ordering::Relaxed) && is_connection_error(&e) => {
+            tracing::warn!("database connection lost, retrying once >>> {}", e);
+            op().await
+        }
+        result => result,
+    }
+}
+
+fn is_connection_error(e: &sqlx::Error) -> bool {
+    // Class 08 is connection_exception; 57P01-57P03 are sent when the server
+    // is shutting down or not yet accepting connections.
+    const SERVER_GOING_AWAY: [&str; 3] = ["57P01", "57P02", "57P03"];
+    match e {
+        sqlx::Error::Io(_) | sqlx::Error::Tls(_) | sqlx::Error::Protocol(_) => true,
+        sqlx::Error::Database(db_err) => db_err
+            .code()
+            .map(|code| code.starts_with("08") || SERVER_GOING_AWAY.contains(&code.as_ref()))
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+fn is_unique_violation(e: &sqlx::Error) -> bool {
+    const UNIQUE_VIOLATION: &str = "23505";
+    matches!(e, sqlx::Error::Database(db_err) if db_err.code().as_deref() == Some(UNIQUE_VIOLATION))
+}
+
+/// The unique index or constraint a failed insert or update ran into.
+fn violated_constraint(e: &sqlx::Error) -> Option<&str> {
+    match e {
+        sqlx::Error::Database(db_err) if is_unique_violation(e) => db_err.constraint(),
+        _ => None,
+    }
+}
+
+/// Escapes `value` so it matches literally inside a `like` pattern using
+/// `escape '\'`, rather than `%` and `_` acting as wildcards.
+fn escape_like(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
 fn append_search_param_to_query<'a>(
     builder: &'a mut QueryBuilder<'a, Postgres>,
     query: &FetchUserQuery,
     skip_ordering: bool,
     skip_pagination: bool,
 ) -> &'a mut QueryBuilder<'a, Postgres> {
-    builder.push(" where username != ");
-    builder.push_bind(query.auth_user.clone());
+    // every filter below is appended as an `and`
+    builder.push(" where true ");
+
+    if !query.include_deactivated {
+        builder.push(" and deactivated_at is null ");
+    }
+
+    if let Some(exclude_user) = &query.exclude_user {
+        builder.push(" and lower(username) != lower(");
+        builder.push_bind(exclude_user.clone());
+        builder.push(")");
+    }
 
     if let Some(username) = &query.username {
-        builder.push(format!(" and username like '%{}%' ", username));
+        builder.push(" and username ilike ");
+        builder.push_bind(format!("%{}%", escape_like(username)));
+        builder.push(r" escape '\' ");
+    }
+
+    if let Some(role) = query.role {
+        builder.push(" and role = ");
+        builder.push_bind(role.as_str());
+    }
+
+    if let Some(invite_code) = &query.invite_code {
+        builder.push(" and lower(invite_code) = lower(");
+        builder.push_bind(invite_code.inner());
+        builder.push(")");
+    }
+
+    if let Some(referred_by) = &query.referred_by {
+        builder.push(" and lower(referred_by) = lower(");
+        builder.push_bind(referred_by.clone());
+        builder.push(")");
+    }
+
+    let cursor = query.cursor.filter(|_| !skip_pagination);
+    if let Some(cursor) = cursor {
+        builder.push(" and (created_on, uid) < (");
+        builder.push_bind(cursor.created_on);
+        builder.push(", ");
+        builder.push_bind(cursor.uid);
+        builder.push(")");
     }
 
     if !skip_ordering {
-        builder.push(" order by created_on desc ");
+        builder.push(query.order_by.as_sql());
     }
 
     if !skip_pagination {
         builder.push(" limit ");
         builder.push_bind(query.limit);
 
-        builder.push(" offset ");
-        builder.push_bind(query.skip);
+        if cursor.is_none() {
+            builder.push(" offset ");
+            builder.push_bind(query.skip);
+        }
     }
 
     builder
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::fields::CasePolicy;
+
+    fn code(value: &str) -> InviteCode {
+        InviteCode::parse(value.to_owned(), 32, CasePolicy::Preserve).unwrap()
+    }
+
+    #[sqlx::test]
+    async fn invite_codes_differing_only_in_case_clash(pool: PgPool) {
+        create_new_user(&pool, &"alice".to_owned().into(), &code("Ali1234"), None)
+            .await
+            .unwrap();
+
+        for clash in ["Ali1234", "ali1234"] {
+            let result = create_new_user(&pool, &"bob".to_owned().into(), &code(clash), None).await;
+            assert!(matches!(result, Err(DatabaseError::InviteCodeTaken)));
+        }
+    }
+
+    #[sqlx::test]
+    async fn usernames_differing_only_in_case_clash(pool: PgPool) {
+        create_new_user(&pool, &"Alice".to_owned().into(), &code("ali1234"), None)
+            .await
+            .unwrap();
+
+        for clash in ["Alice", "alice", "ALICE"] {
+            let result =
+                create_new_user(&pool, &clash.to_owned().into(), &code("ali5678"), None).await;
+            assert!(matches!(result, Err(DatabaseError::Conflict)), "{clash}");
+        }
+
+        // found whatever the casing, but keeps the one it registered with
+        let user = get_user_by_username(&pool, &"aLiCe".to_owned().into())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(user.username.inner(), "Alice");
+        assert_eq!(count_users(&pool).await.unwrap(), 1);
+    }
+
+    #[test]
+    fn cursors_round_trip() {
+        let cursor = UserCursor {
+            created_on: OffsetDateTime::from_unix_timestamp_nanos(1_696_163_445_123_456_789)
+                .unwrap(),
+            uid: Uuid::new_v4(),
+        };
+
+        let decoded = UserCursor::decode(&cursor.encode()).unwrap();
+        assert_eq!(decoded.created_on, cursor.created_on);
+        assert_eq!(decoded.uid, cursor.uid);
+    }
+
+    #[test]
+    fn malformed_cursors_are_rejected() {
+        let encode = |raw: &str| URL_SAFE_NO_PAD.encode(raw);
+        for cursor in [
+            "".to_owned(),
+            "not base64!".to_owned(),
+            encode("123"),
+            encode("abc:00000000-0000-0000-0000-000000000000"),
+            encode("123:not-a-uuid"),
+            encode(&format!("{}:{}", i128::MAX, Uuid::nil())),
+        ] {
+            assert!(UserCursor::decode(&cursor).is_none(), "{cursor}");
+        }
+    }
+
+    /// The first page of every active user, newest first.
+    fn everyone() -> FetchUserQuery {
+        FetchUserQuery {
+            username: None,
+            invite_code: None,
+            referred_by: None,
+            exclude_user: None,
+            role: None,
+            skip: 0,
+            limit: 100,
+            with_total: false,
+            order_by: OrderBy::CreatedOn,
+            cursor: None,
+            include_deactivated: false,
+        }
+    }
+
+    async fn insert_users(pool: &PgPool, usernames: &[&str]) {
+        for (n, username) in usernames.iter().enumerate() {
+            create_new_user(
+                pool,
+                &username.to_string().into(),
+                &code(&format!("code{n}")),
+                None,
+            )
+            .await
+            .unwrap();
+        }
+    }
+
+    /// Registers each of `usernames` as referred by `referrer`.
+    async fn refer(pool: &PgPool, referrer: &str, usernames: &[&str]) {
+        for username in usernames {
+            create_new_user(
+                pool,
+                &username.to_string().into(),
+                &InviteCode::new(username, 6, CasePolicy::Preserve),
+                Some(referrer.to_owned().into()),
+            )
+            .await
+            .unwrap();
+        }
+    }
+
+    async fn usernames(pool: &PgPool, query: FetchUserQuery) -> Vec<String> {
+        let (users, _) = fetch_users(pool, query).await.unwrap();
+        let mut usernames: Vec<String> =
+            users.into_iter().map(|(_, u)| u.username.inner()).collect();
+        usernames.sort();
+        usernames
+    }
+
+    #[test]
+    fn like_wildcards_are_escaped() {
+        assert_eq!(escape_like("alice"), "alice");
+        assert_eq!(escape_like("a_b%c"), r"a\_b\%c");
+        assert_eq!(escape_like(r"a\b"), r"a\\b");
+    }
+
+    #[sqlx::test]
+    async fn username_search_matches_wildcards_literally(pool: PgPool) {
+        insert_users(&pool, &["alice", "al_ce", "100%real", "bob"]).await;
+
+        let search = |username: &str| FetchUserQuery {
+            username: Some(username.to_owned()),
+            ..everyone()
+        };
+        assert_eq!(usernames(&pool, search("_")).await, ["al_ce"]);
+        assert_eq!(usernames(&pool, search("%")).await, ["100%real"]);
+        assert_eq!(usernames(&pool, search("ALI")).await, ["alice"]);
+    }
+
+    #[sqlx::test]
+    async fn username_search_is_not_injectable(pool: PgPool) {
+        insert_users(&pool, &["alice", "o'brien"]).await;
+
+        let search = |username: &str| FetchUserQuery {
+            username: Some(username.to_owned()),
+            ..everyone()
+        };
+        assert!(usernames(&pool, search("' or 1=1 --")).await.is_empty());
+        assert!(usernames(&pool, search("'; drop table users; --"))
+            .await
+            .is_empty());
+        assert_eq!(usernames(&pool, search("o'b")).await, ["o'brien"]);
+        assert_eq!(usernames(&pool, everyone()).await, ["alice", "o'brien"]);
+    }
+
+    #[sqlx::test]
+    async fn totals_are_only_counted_when_asked_for(pool: PgPool) {
+        insert_users(&pool, &["alice", "bob", "carol"]).await;
+        let page = |with_total| FetchUserQuery {
+            limit: 2,
+            with_total,
+            ..everyone()
+        };
+
+        let (users, total) = fetch_users(&pool, page(false)).await.unwrap();
+        assert_eq!(users.len(), 2);
+        assert_eq!(total, None);
+
+        // the total covers every match, not just the page
+        let (users, total) = fetch_users(&pool, page(true)).await.unwrap();
+        assert_eq!(users.len(), 2);
+        assert_eq!(total, Some(3));
+    }
+
+    #[sqlx::test]
+    async fn leaderboard_ranks_by_active_referrals(pool: PgPool) {
+        insert_users(&pool, &["alice", "erin"]).await;
+        refer(&pool, "alice", &["bob", "carol"]).await;
+        refer(&pool, "bob", &["dave"]).await;
+        refer(&pool, "erin", &["frank", "grace"]).await;
+        for username in ["erin", "grace"] {
+            deactivate_user(&pool, &username.to_owned().into())
+                .await
+                .unwrap();
+        }
+
+        let (users, count) = fetch_leaderboard(&pool, 0, 10).await.unwrap();
+        let ranked: Vec<(i64, String, i64)> = users
+            .into_iter()
+            .map(|(rank, u)| (rank, u.username.inner(), u.referrals))
+            .collect();
+        // users with no referrals tie for the last rank
+        assert_eq!(
+            &ranked[..2],
+            [(1, "alice".to_owned(), 2), (2, "bob".to_owned(), 1)]
+        );
+        assert!(ranked[2..]
+            .iter()
+            .all(|(rank, _, referrals)| (*rank, *referrals) == (3, 0)));
+        assert_eq!(ranked.len(), 5);
+        assert_eq!(count, 5);
+
+        let (users, _) = fetch_leaderboard(&pool, 1, 1).await.unwrap();
+        assert_eq!(users[0].1.username.inner(), "bob");
+    }
+
+    #[sqlx::test]
+    async fn users_can_be_sorted_by_referrals(pool: PgPool) {
+        insert_users(&pool, &["alice", "bob"]).await;
+        refer(&pool, "bob", &["carol", "dave"]).await;
+        refer(&pool, "alice", &["erin"]).await;
+
+        let (users, _) = fetch_users(
+            &pool,
+            FetchUserQuery {
+                order_by: OrderBy::Referrals,
+                limit: 3,
+                ..everyone()
+            },
+        )
+        .await
+        .unwrap();
+        let usernames: Vec<String> = users.into_iter().map(|(_, u)| u.username.inner()).collect();
+        // ties go to whoever joined first
+        assert_eq!(usernames, ["bob", "alice", "carol"]);
+    }
+
+    #[sqlx::test]
+    async fn cohort_rank_only_counts_users_from_the_same_week(pool: PgPool) {
+        insert_users(&pool, &["alice", "bob", "carol"]).await;
+        refer(&pool, "alice", &["dave"]).await;
+        sqlx::query("update users set created_on = created_on - interval '14 days' where username = 'carol'")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let rank = |username: &str| {
+            let pool = pool.clone();
+            let username: Username = username.to_owned().into();
+            async move { get_cohort_rank(&pool, &username).await.unwrap() }
+        };
+
+        let alice = rank("alice").await.unwrap();
+        assert_eq!((alice.rank, alice.cohort_size), (1, 3));
+        assert!(alice.cohort_start <= OffsetDateTime::now_utc());
+        let bob = rank("BOB").await.unwrap();
+        assert_eq!((bob.rank, bob.cohort_size), (2, 3));
+        assert_eq!(bob.cohort_start, alice.cohort_start);
+
+        let carol = rank("carol").await.unwrap();
+        assert_eq!((carol.rank, carol.cohort_size), (1, 1));
+        assert_eq!(
+            carol.cohort_start,
+            alice.cohort_start - time::Duration::weeks(2)
+        );
+
+        assert!(rank("nobody").await.is_none());
+    }
+
+    /// Runs `with_reconnect` over an op that fails with each of `errors` in
+    /// turn before succeeding, and returns the result with the attempts made.
+    async fn retry(errors: Vec<sqlx::Error>) -> (Result<(), sqlx::Error>, usize) {
+        let mut errors = errors.into_iter();
+        let mut attempts = 0;
+        let result = with_reconnect(|| {
+            attempts += 1;
+            let next = errors.next();
+            async move { next.map_or(Ok(()), Err) }
+        })
+        .await;
+        (result, attempts)
+    }
+
+    fn connection_reset() -> sqlx::Error {
+        sqlx::Error::Io(std::io::ErrorKind::ConnectionReset.into())
+    }
+
+    #[tokio::test]
+    async fn dropped_connections_are_retried_once() {
+        let (result, attempts) = retry(vec![connection_reset()]).await;
+        assert!(result.is_ok());
+        assert_eq!(attempts, 2);
+
+        let (result, attempts) = retry(vec![connection_reset(), connection_reset()]).await;
+        assert!(matches!(result, Err(sqlx::Error::Io(_))));
+        assert_eq!(attempts, 2);
+    }
+
+    #[tokio::test]
+    async fn rejected_queries_are_not_retried() {
+        for error in [
+            sqlx::Error::RowNotFound,
+            sqlx::Error::ColumnNotFound("uid".to_owned()),
+        ] {
+            let (result, attempts) = retry(vec![error]).await;
+            assert!(result.is_err());
+            assert_eq!(attempts, 1);
+        }
+    }
+
+    #[sqlx::test]
+    async fn server_errors_are_classified_by_sqlstate(pool: PgPool) {
+        let raise = |code: &'static str| {
+            let pool = pool.clone();
+            async move {
+                sqlx::query(&format!(
+                    "do $$ begin raise exception 'test' using errcode = '{code}'; end $$"
+                ))
+                .execute(&pool)
+                .await
+                .unwrap_err()
+            }
+        };
+
+        for code in ["08006", "57P01", "57P03"] {
+            assert!(is_connection_error(&raise(code).await), "{code}");
+        }
+        for code in ["23505", "22012", "57014"] {
+            assert!(!is_connection_error(&raise(code).await), "{code}");
+        }
+    }
+
+    #[sqlx::test]
+    async fn queries_survive_a_terminated_connection(pool: PgPool) {
+        let mut doomed = pool.acquire().await.unwrap();
+        let pid: i32 = sqlx::query_scalar("select pg_backend_pid()")
+            .fetch_one(&mut doomed)
+            .await
+            .unwrap();
+        let terminated: bool = sqlx::query_scalar("select pg_terminate_backend($1)")
+            .bind(pid)
+            .fetch_one(&mut pool.acquire().await.unwrap())
+            .await
+            .unwrap();
+        assert!(terminated);
+        // back in the pool, dead
+        drop(doomed);
+
+        insert_users(&pool, &["alice"]).await;
+        assert_eq!(count_users(&pool).await.unwrap(), 1);
+    }
+
+    #[sqlx::test]
+    async fn users_can_be_filtered_by_invite_code_and_referrer(pool: PgPool) {
+        insert_users(&pool, &["alice", "bob"]).await;
+        refer(&pool, "alice", &["carol", "dave"]).await;
+        refer(&pool, "bob", &["erin"]).await;
+
+        let by_code = |value: &str| FetchUserQuery {
+            invite_code: Some(code(value)),
+            ..everyone()
+        };
+        assert_eq!(usernames(&pool, by_code("CODE0")).await, ["alice"]);
+        assert!(usernames(&pool, by_code("code9")).await.is_empty());
+
+        let by_referrer = |value: &str| FetchUserQuery {
+            referred_by: Some(value.to_owned()),
+            ..everyone()
+        };
+        assert_eq!(
+            usernames(&pool, by_referrer("ALICE")).await,
+            ["carol", "dave"]
+        );
+        assert_eq!(usernames(&pool, by_referrer("bob")).await, ["erin"]);
+        assert!(usernames(&pool, by_referrer("carol")).await.is_empty());
+
+        let (_, total) = fetch_users(
+            &pool,
+            FetchUserQuery {
+                with_total: true,
+                limit: 1,
+                ..by_referrer("alice")
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(total, Some(2));
+    }
+
+    #[sqlx::test]
+    async fn login_history_is_per_user_and_most_recent_first(pool: PgPool) {
+        insert_users(&pool, &["alice", "bob"]).await;
+        let alice: Username = "alice".to_owned().into();
+        for ip in ["10.0.0.1", "10.0.0.2", "::1"] {
+            record_login_event(&pool, &alice, ip.parse().unwrap())
+                .await
+                .unwrap();
+        }
+        record_login_event(&pool, &"bob".to_owned().into(), "10.0.0.9".parse().unwrap())
+            .await
+            .unwrap();
+
+        let (events, count) = fetch_login_events(&pool, &alice, 0, 2).await.unwrap();
+        let ips: Vec<_> = events.iter().map(|e| e.ip_address.as_deref()).collect();
+        assert_eq!(ips, [Some("::1"), Some("10.0.0.2")]);
+        assert!(events[0].logged_in_at >= events[1].logged_in_at);
+        assert_eq!(count, 3);
+
+        let (events, _) = fetch_login_events(&pool, &alice, 2, 2).await.unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].ip_address.as_deref(), Some("10.0.0.1"));
+    }
+}
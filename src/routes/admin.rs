@@ -0,0 +1,263 @@
+//! Routes only admins can reach, layered with `require_admin` inside
+//! `check_auth`.
+
+use std::sync::Arc;
+
+use crate::{
+    app::AppState,
+    config::{ConfigIssue, Severity},
+    domain::{
+        errors::ApiError,
+        fields::{Role, User},
+    },
+    repository::{fetch_users, get_user_by_username, FetchUserQuery},
+    routes::user::Pagination,
+};
+use axum::{
+    extract::{rejection::QueryRejection, Path, Query, State},
+    http::StatusCode,
+    Extension, Json,
+};
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize)]
+pub struct AdminUsersParams {
+    username: Option<String>,
+    role: Option<Role>,
+    page: Option<i64>,
+    limit: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct AdminUsersResponse {
+    users: Vec<User>,
+    #[serde(flatten)]
+    pagination: Pagination,
+}
+
+#[derive(Serialize)]
+pub struct ConfigValidationResponse {
+    ok: bool,
+    issues: Vec<ConfigIssue>,
+}
+
+#[derive(Deserialize)]
+pub struct MaintenanceRequest {
+    enabled: bool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MaintenanceResponse {
+    maintenance_mode: bool,
+}
+
+/// Every user, the caller and deactivated accounts included.
+pub async fn list_users(
+    State(state): State<Arc<AppState>>,
+    params: Result<Query<AdminUsersParams>, QueryRejection>,
+) -> Result<Json<AdminUsersResponse>, ApiError> {
+    let Query(params) = params?;
+    let pool = state.get_read_pool();
+    let (page, limit, skip) = Pagination::bounds(
+        params.page,
+        params.limit,
+        state.config.application.max_page_size,
+    );
+
+    let query = FetchUserQuery {
+        username: params.username,
+        invite_code: None,
+        referred_by: None,
+        exclude_user: None,
+        role: params.role,
+        skip,
+        limit,
+        with_total: true,
+        order_by: Default::default(),
+        cursor: None,
+        include_deactivated: true,
+    };
+
+    let (users, count) = fetch_users(&pool, query).await?;
+    Ok(Json(AdminUsersResponse {
+        users: users.into_iter().map(|(_, user)| user).collect(),
+        pagination: Pagination::from_count(page, limit, count.unwrap_or(0)),
+    }))
+}
+
+/// Who has admin access, the same listing as `list_users` narrowed to admins.
+pub async fn list_admins(
+    state: State<Arc<AppState>>,
+    params: Result<Query<AdminUsersParams>, QueryRejection>,
+) -> Result<Json<AdminUsersResponse>, ApiError> {
+    let Query(mut params) = params?;
+    params.role = Some(Role::Admin);
+    list_users(state, Ok(Query(params))).await
+}
+
+/// Invalidates every access and refresh token issued to `username` so far.
+pub async fn revoke_user_tokens(
+    State(state): State<Arc<AppState>>,
+    Extension(admin): Extension<User>,
+    Path(username): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    let pool = state.get_pool();
+    let user = get_user_by_username(&pool, &username.into())
+        .await?
+        .ok_or(ApiError::NotFound)?;
+
+    tracing::warn!(
+        "{} revoked all tokens of >>> {}",
+        admin.username,
+        user.username
+    );
+    state.revoke_all_tokens(user.username.as_ref());
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Re-runs the startup config checks against the running config. `ok` is
+/// false when any of them would have stopped the server from starting.
+pub async fn validate_config(State(state): State<Arc<AppState>>) -> Json<ConfigValidationResponse> {
+    let issues = state.config.issues();
+    Json(ConfigValidationResponse {
+        ok: issues.iter().all(|i| i.severity != Severity::Error),
+        issues,
+    })
+}
+
+pub async fn set_maintenance(
+    State(state): State<Arc<AppState>>,
+    Extension(admin): Extension<User>,
+    Json(payload): Json<MaintenanceRequest>,
+) -> Json<MaintenanceResponse> {
+    tracing::warn!(
+        "{} set maintenance mode >>> {}",
+        admin.username,
+        payload.enabled
+    );
+    state.set_maintenance(payload.enabled);
+    Json(MaintenanceResponse {
+        maintenance_mode: state.in_maintenance(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{app::TestApp, config::test_config};
+    use axum::http::{Method, StatusCode};
+    use secrecy::ExposeSecret;
+    use serde_json::Value;
+    use sqlx::PgPool;
+
+    /// Registers alice as an admin and returns her token.
+    async fn admin_token(app: &TestApp, pool: &PgPool) -> String {
+        let token = app.authenticate("alice", None).await["token"]
+            .as_str()
+            .unwrap()
+            .to_owned();
+        sqlx::query("update users set role = 'admin' where username = 'alice'")
+            .execute(pool)
+            .await
+            .unwrap();
+        token
+    }
+
+    fn usernames(body: &Value) -> Vec<&str> {
+        let mut usernames: Vec<&str> = body["users"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|u| u["username"].as_str().unwrap())
+            .collect();
+        usernames.sort();
+        usernames
+    }
+
+    #[sqlx::test]
+    async fn lists_only_admins(pool: PgPool) {
+        let app = TestApp::new(test_config(), pool.clone());
+        let token = admin_token(&app, &pool).await;
+        for username in ["bob", "carol", "dave"] {
+            app.authenticate(username, None).await;
+        }
+        sqlx::query("update users set role = 'admin' where username = 'carol'")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let (status, body) = app
+            .call(Method::GET, "/admin/admins", Some(&token), None)
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(usernames(&body), ["alice", "carol"]);
+        assert_eq!(body["totalItems"], 2);
+
+        let (status, body) = app
+            .call(Method::GET, "/admin/users?role=admin", Some(&token), None)
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(usernames(&body), ["alice", "carol"]);
+
+        let (_, body) = app
+            .call(Method::GET, "/admin/users?role=member", Some(&token), None)
+            .await;
+        assert_eq!(usernames(&body), ["bob", "dave"]);
+
+        let (status, _) = app
+            .call(Method::GET, "/admin/users?role=owner", Some(&token), None)
+            .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[sqlx::test]
+    async fn validates_the_running_config(pool: PgPool) {
+        let app = TestApp::new(test_config(), pool.clone());
+        let token = admin_token(&app, &pool).await;
+
+        let (status, body) = app
+            .call(Method::GET, "/admin/config/validate", Some(&token), None)
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["ok"], true);
+        // the checked-in secrets are placeholders
+        assert!(body["issues"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .all(|i| i["severity"] == "warning"));
+    }
+
+    #[sqlx::test]
+    async fn reports_every_issue_with_an_inconsistent_config(pool: PgPool) {
+        let mut config = test_config();
+        config.database.min_connections = config.database.max_connections + 1;
+        config.jwt.refresh_secret = config.jwt.secret.clone();
+        let app = TestApp::new(config, pool.clone());
+        let token = admin_token(&app, &pool).await;
+
+        let (status, body) = app
+            .call(Method::GET, "/admin/config/validate", Some(&token), None)
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["ok"], false);
+        let errors: Vec<&str> = body["issues"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|i| i["severity"] == "error")
+            .map(|i| i["message"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                "database.min_connections must not exceed database.max_connections",
+                "jwt.refresh_secret must differ from jwt.secret",
+            ]
+        );
+        assert!(!body
+            .to_string()
+            .contains(test_config().jwt.secret.expose_secret()));
+    }
+}
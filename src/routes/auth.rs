@@ -1,117 +1,823 @@
 use crate::{
     app::{AppState, Db},
-    config::Config,
+    config::{Config, JwtConfig},
     domain::{
-        errors::ApiError,
-        events::{AppEvent, NewReferralEvent},
-        fields::{InviteCode, Username},
+        errors::{ApiError, DatabaseError, JWTError},
+        events::{AppEvent, NewReferralEvent, ReferralMilestoneEvent},
+        fields::{Claims, InviteCode, Role, User, Username},
+    },
+    repository::{
+        create_new_user, get_user_by_invite_code, get_user_by_username, record_login_event,
+        record_referral_milestones,
+    },
+    utils::{
+        client_ip::ClientIp,
+        idempotency::{CachedResponse, Fingerprint, IdempotencyStatus, IdempotencyStore},
+        jwt::{
+            decode_auth_token, decode_refresh_token, generate_auth_token, generate_refresh_token,
+            JwtKeys,
+        },
+        revocation::RevocationStore,
     },
-    repository::{create_new_user, get_user_by_invite_code, get_user_by_username},
-    utils::jwt::{decode_auth_token, generate_auth_token},
 };
 use axum::{
     extract::State,
-    headers::{authorization::Bearer, Authorization},
-    http::{Request, StatusCode},
+    http::{header, HeaderMap, Request, StatusCode},
     middleware::Next,
     response::{IntoResponse, Response},
-    Json, TypedHeader,
+    Extension, Json,
 };
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
+use sha2::{Digest, Sha256};
+use std::{
+    net::IpAddr,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use uuid::Uuid;
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct AuthenticateRequest {
-    username: Username,
-    invitation_code: Option<InviteCode>,
+    username: String,
+    invitation_code: Option<String>,
+}
+
+impl AuthenticateRequest {
+    /// Idempotency keys are only unique per user, so two users picking the
+    /// same key never see each other's responses. Usernames are matched
+    /// ignoring case and surrounding whitespace, the same as when logging in.
+    fn idempotency_scope(&self, key: &str) -> String {
+        format!("{}:{}", self.username.trim().to_lowercase(), key)
+    }
+
+    fn fingerprint(&self) -> Fingerprint {
+        // serializing can't fail for two strings
+        let payload = serde_json::to_vec(self).unwrap_or_default();
+        Sha256::digest(payload).into()
+    }
 }
 
 #[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct AuthenticateResponse {
     token: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    refresh_token: Option<String>,
+}
+
+impl AuthenticateResponse {
+    fn issue(
+        username: &Username,
+        jwt_config: &JwtConfig,
+        keys: &JwtKeys,
+    ) -> Result<Self, JWTError> {
+        let session_id = Uuid::new_v4().to_string();
+        Ok(Self {
+            token: generate_auth_token(username, &session_id, jwt_config, keys)?,
+            refresh_token: jwt_config
+                .refresh_tokens
+                .then(|| generate_refresh_token(username, &session_id, jwt_config, keys))
+                .transpose()?,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RefreshTokenRequest {
+    refresh_token: String,
+}
+
+#[derive(Serialize)]
+pub struct RefreshTokenResponse {
+    token: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthStatusResponse {
+    valid: bool,
+    expires_in_secs: u64,
+}
+
+impl AuthStatusResponse {
+    /// Status of a token that passed `check_auth`, as of `now` (unix secs).
+    /// Within the expiry leeway it is still valid but expires in 0 secs.
+    fn at(claims: &Claims, now: u64) -> Self {
+        Self {
+            valid: true,
+            expires_in_secs: (claims.exp as u64).saturating_sub(now),
+        }
+    }
+}
+
+/// Releases an idempotency key if the request owning it doesn't finish, so
+/// a retry isn't stuck on `InProgress` until the key expires.
+struct IdempotencyClaim {
+    store: Arc<dyn IdempotencyStore>,
+    key: String,
+    completed: bool,
+}
+
+impl IdempotencyClaim {
+    fn complete(mut self, response: CachedResponse) {
+        self.store.complete(&self.key, response);
+        self.completed = true;
+    }
 }
 
-impl From<String> for AuthenticateResponse {
-    fn from(token: String) -> Self {
-        Self { token }
+impl Drop for IdempotencyClaim {
+    fn drop(&mut self) {
+        if !self.completed {
+            self.store.abandon(&self.key);
+        }
     }
 }
 
+/// Authenticates with optional `Idempotency-Key` support: a retry carrying
+/// the same key and payload gets the first response back instead of running
+/// again.
 pub async fn authenticate(
     State(state): State<Arc<AppState>>,
+    ClientIp(client_ip): ClientIp,
+    headers: HeaderMap,
     Json(payload): Json<AuthenticateRequest>,
+) -> Result<Response, ApiError> {
+    let key = match headers.get("idempotency-key") {
+        Some(value) => value
+            .to_str()
+            .ok()
+            .map(str::trim)
+            .filter(|k| !k.is_empty() && k.len() <= 255)
+            .ok_or(ApiError::InvalidIdempotencyKey)?
+            .to_owned(),
+        None => {
+            let tokens = authenticate_user(state, client_ip, payload).await?;
+            return Ok(tokens.into_response());
+        }
+    };
+
+    let key = payload.idempotency_scope(&key);
+    let store = state.get_idempotency_store();
+    let ttl = Duration::from_secs(state.config.application.idempotency_ttl_secs);
+    match store.begin(&key, payload.fingerprint(), ttl) {
+        IdempotencyStatus::New => {}
+        IdempotencyStatus::Completed(cached) => {
+            tracing::info!("replaying authenticate response >>> {}", key);
+            return Ok(([(header::CONTENT_TYPE, "application/json")], cached.body).into_response());
+        }
+        IdempotencyStatus::InProgress => return Err(ApiError::IdempotencyKeyInProgress),
+        IdempotencyStatus::Mismatch => return Err(ApiError::IdempotencyKeyReused),
+    }
+
+    let claim = IdempotencyClaim {
+        store,
+        key,
+        completed: false,
+    };
+    // errors aren't cached, the claim is released on drop and a retry runs again
+    let Json(tokens) = authenticate_user(state, client_ip, payload).await?;
+    match serde_json::to_string(&tokens) {
+        Ok(body) => claim.complete(CachedResponse { body }),
+        Err(e) => tracing::error!("caching authenticate response failed >>> {}", e),
+    }
+
+    Ok(Json(tokens).into_response())
+}
+
+async fn authenticate_user(
+    state: Arc<AppState>,
+    client_ip: IpAddr,
+    payload: AuthenticateRequest,
 ) -> Result<Json<AuthenticateResponse>, ApiError> {
     let pool = state.get_pool();
-    tracing::info!("authenticating user >>> {}", payload.username);
-    let user = get_user_by_username(&pool, &payload.username).await?;
+    // existing accounts may predate the username rules, so they're only
+    // enforced when registering
+    let username = Username::from(payload.username.trim().to_owned());
+
+    tracing::info!("authenticating user >>> {}", username);
+    let user = get_user_by_username(&pool, &username).await?;
 
     if let Some(user) = user {
-        let _ = state.get_sender().send(AppEvent::NewLogin(user.clone()));
-        let token = generate_auth_token(&user.username, &state.config.jwt)?;
-        return Ok(Json(token.into()));
+        return login(&state, user, client_ip).await;
     }
 
-    let referrer_username = if let Some(invite_code) = payload.invitation_code {
+    let username = Username::parse(
+        username.inner(),
+        state.config.username.min_length,
+        state.config.username.max_length,
+    )
+    .map_err(|e| {
+        tracing::info!("rejected username >>> {}", e);
+        ApiError::InvalidUsername(format!("username {}", e))
+    })?;
+
+    state.ensure_writable()?;
+
+    // an invite code only matters when registering, so a stale or garbled
+    // one never gets in the way of logging in
+    let invite_code = payload
+        .invitation_code
+        .map(|code| {
+            InviteCode::parse(
+                code,
+                state.config.invite_code.max_length,
+                state.config.invite_code.case,
+            )
+        })
+        .transpose()
+        .map_err(|e| {
+            tracing::info!("rejected invite code >>> {}", e);
+            ApiError::InvalidInviteCode
+        })?;
+
+    let referrer = if let Some(invite_code) = invite_code {
         get_user_by_invite_code(&pool, &invite_code)
             .await
             .map_err(|_| ApiError::InvalidInviteCode)?
-            .map(|u| u.username)
     } else {
         None
     };
 
-    let invite_code = {
-        let username = payload.username.as_ref();
-        let mut code = InviteCode::new(username);
-        while get_user_by_invite_code(&pool, &code).await?.is_some() {
-            code = InviteCode::new(username);
+    let referrer_username = match referrer {
+        Some(referrer) if referrer.deactivated_at.is_some() => {
+            tracing::info!(
+                "referral by {} not credited >>> referrer is deactivated",
+                referrer.username
+            );
+            None
+        }
+        Some(referrer) if !state.config.referral.within_attribution_window(&referrer) => {
+            tracing::info!(
+                "referral by {} not credited >>> invite code is outside the attribution window",
+                referrer.username
+            );
+            None
+        }
+        Some(referrer) if !state.try_credit_referral(client_ip) => {
+            tracing::warn!(
+                "referral by {} not credited >>> {} exceeded per-ip referral limit",
+                referrer.username,
+                client_ip
+            );
+            None
+        }
+        referrer => referrer.map(|r| r.username),
+    };
+
+    let credited = referrer_username.is_some();
+    let created = create_user(&state, &username, referrer_username).await;
+    if credited && created.is_err() {
+        state.refund_referral_credit(client_ip);
+    }
+
+    match created {
+        Ok(()) => {}
+        // someone registered the same username between our lookup and insert,
+        // so this request becomes a login to that account
+        Err(DatabaseError::Conflict) => {
+            tracing::info!("user {} registered concurrently, logging in", username);
+            let user = get_user_by_username(&pool, &username)
+                .await?
+                .ok_or(ApiError::ServerError)?;
+            return login(&state, user, client_ip).await;
+        }
+        Err(DatabaseError::InviteCodeTaken) => return Err(ApiError::InviteCodeExhausted),
+        Err(e) => return Err(e.into()),
+    }
+
+    let user = get_user_by_username(&pool, &username).await?.unwrap();
+    if let Some(referrer) = &user.referred_by {
+        state.publish(AppEvent::NewReferral(NewReferralEvent {
+            referred_user: user.clone().username,
+            referrer: referrer.clone(),
+        }));
+        publish_milestones(&state, referrer).await;
+    }
+
+    state.publish(AppEvent::NewRegister(user.clone()));
+    state.get_metrics().record_registration();
+    record_login(&state, &user.username, client_ip).await;
+    let tokens =
+        AuthenticateResponse::issue(&user.username, &state.config.jwt, &state.get_jwt_keys())?;
+    Ok(Json(tokens))
+}
+
+/// Inserts `username` with a freshly generated invite code. A code can still
+/// be taken between the lookup and the insert, so a clash on the invite code
+/// generates another one, while a clash on the username is returned as
+/// `DatabaseError::Conflict` for the caller to handle.
+async fn create_user(
+    state: &AppState,
+    username: &Username,
+    referred_by: Option<Username>,
+) -> Result<(), DatabaseError> {
+    let pool = state.get_pool();
+    let config = &state.config.invite_code;
+    let suffix_length = state.invite_suffix_length().await;
+
+    for _ in 0..config.max_generation_attempts {
+        let code = InviteCode::new(username.as_ref(), suffix_length, config.case);
+        if get_user_by_invite_code(&pool, &code).await?.is_some() {
+            continue;
         }
-        code
+
+        match create_new_user(&pool, username, &code, referred_by.clone()).await {
+            Err(DatabaseError::InviteCodeTaken) => continue,
+            result => return result,
+        }
+    }
+
+    tracing::error!(
+        "no unique invite code for {} after {} attempts",
+        username,
+        config.max_generation_attempts
+    );
+    Err(DatabaseError::InviteCodeTaken)
+}
+
+/// Publishes a `ReferralMilestone` for each configured count `referrer` has
+/// reached but wasn't celebrated for yet. Reached milestones are persisted,
+/// so each fires once even when the count jumps past it or referrals race.
+pub(crate) async fn publish_milestones(state: &AppState, referrer: &Username) {
+    let pool = state.get_pool();
+    let referrer = match get_user_by_username(&pool, referrer).await {
+        Ok(Some(referrer)) => referrer,
+        // the referral itself went through, a missed milestone isn't worth failing it
+        _ => return,
     };
 
-    let _ = create_new_user(&pool, &payload.username, &invite_code, referrer_username).await?;
-    let user = get_user_by_username(&pool, &payload.username)
+    let reached = record_referral_milestones(
+        &pool,
+        &referrer.username,
+        referrer.referrals,
+        &state.config.referral.milestones,
+    )
+    .await
+    .unwrap_or_default();
+    for milestone in reached {
+        state.publish(AppEvent::ReferralMilestone(ReferralMilestoneEvent {
+            user: referrer.username.clone(),
+            total: milestone,
+        }));
+    }
+}
+
+async fn login(
+    state: &AppState,
+    user: User,
+    client_ip: IpAddr,
+) -> Result<Json<AuthenticateResponse>, ApiError> {
+    if user.deactivated_at.is_some() {
+        tracing::info!(
+            "rejected login to deactivated account >>> {}",
+            user.username
+        );
+        return Err(ApiError::AccountDeactivated);
+    }
+
+    state.publish(AppEvent::NewLogin(user.clone()));
+    state.get_metrics().record_login();
+    record_login(state, &user.username, client_ip).await;
+    let tokens =
+        AuthenticateResponse::issue(&user.username, &state.config.jwt, &state.get_jwt_keys())?;
+    Ok(Json(tokens))
+}
+
+/// Adds to the user's login history. The history is an audit trail, so a
+/// failed write is logged rather than failing the login.
+async fn record_login(state: &AppState, username: &Username, client_ip: IpAddr) {
+    let _ = record_login_event(&state.get_pool(), username, client_ip).await;
+}
+
+pub async fn refresh_token(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<RefreshTokenRequest>,
+) -> Result<Json<RefreshTokenResponse>, ApiError> {
+    let claims = decode_refresh_token(&payload.refresh_token, &state.get_jwt_keys())?;
+    if is_revoked(state.get_revocation_store().as_ref(), &claims) {
+        return Err(ApiError::InvalidToken);
+    }
+    // the refresh token outlives a deleted account
+    let user = get_user_by_username(&state.get_pool(), &claims.sub.clone().into())
         .await?
-        .unwrap();
-    if user.referred_by.is_some() {
-        let _ = state
-            .get_sender()
-            .send(AppEvent::NewReferral(NewReferralEvent {
-                referred_user: user.clone().username,
-                referrer: user.clone().referred_by.unwrap(),
-            }));
+        .ok_or(ApiError::InvalidToken)?;
+    if user.deactivated_at.is_some() {
+        return Err(ApiError::AccountDeactivated);
     }
+    tracing::info!("refreshing access token >>> {}", user.username);
+    let token = generate_auth_token(
+        &user.username,
+        &claims.sid,
+        &state.config.jwt,
+        &state.get_jwt_keys(),
+    )?;
+    Ok(Json(RefreshTokenResponse { token }))
+}
+
+/// Whether the token itself, its login session or every token of its user
+/// was revoked.
+fn is_revoked(store: &dyn RevocationStore, claims: &Claims) -> bool {
+    store.is_revoked(&claims.jti)
+        || (!claims.sid.is_empty() && store.is_revoked(&claims.sid))
+        || store.is_revoked_for(&claims.sub, claims.iat as u64)
+}
+
+/// Extracts the token from an `Authorization` header value. Strict mode only
+/// accepts the exact `Bearer <token>` form, lenient mode also accepts any
+/// casing of the scheme and surrounding whitespace.
+fn bearer_token(value: &str, strict: bool) -> Option<&str> {
+    let token = if strict {
+        value.strip_prefix("Bearer ")?
+    } else {
+        let (scheme, token) = value.trim().split_once(char::is_whitespace)?;
+        if !scheme.eq_ignore_ascii_case("bearer") {
+            return None;
+        }
+        token.trim()
+    };
 
-    let _ = state.get_sender().send(AppEvent::NewRegister(user.clone()));
-    let token = generate_auth_token(&user.username, &state.config.jwt)?;
-    Ok(Json(token.into()))
+    (!token.is_empty()).then_some(token)
 }
 
-pub async fn check_auth<B>(
-    TypedHeader(auth): TypedHeader<Authorization<Bearer>>,
-    mut request: Request<B>,
-    next: Next<B>,
-) -> Response {
+pub async fn check_auth<B>(mut request: Request<B>, next: Next<B>) -> Response {
     let config = match request.extensions().get::<Config>() {
         Some(c) => c,
         None => return (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
     };
 
-    let token = decode_auth_token(auth.token(), &config.jwt);
+    let auth = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| bearer_token(v, config.jwt.strict_bearer));
+
+    let jwt_keys = match request.extensions().get::<Arc<JwtKeys>>() {
+        Some(k) => k,
+        None => return (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+    };
+
+    let claims = match auth.map(|auth| decode_auth_token(auth, jwt_keys)) {
+        Some(Ok(claims)) => claims,
+        Some(Err(e)) => {
+            return match ApiError::from(e) {
+                ApiError::TokenExpired if !config.jwt.report_expired_tokens => {
+                    ApiError::InvalidToken.into_response()
+                }
+                e => e.into_response(),
+            }
+        }
+        None => return ApiError::InvalidToken.into_response(),
+    };
 
     let db = match request.extensions().get::<Db>() {
         Some(s) => s,
         None => return (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
     };
 
-    if let Ok(claims) = token {
-        if let Ok(Some(user)) = get_user_by_username(&db.inner(), &claims.sub.into()).await {
-            request.extensions_mut().insert(user);
-            let response = next.run(request).await;
-            return response;
+    let revocation_store = match request.extensions().get::<Arc<dyn RevocationStore>>() {
+        Some(s) => s,
+        None => return (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+    };
+
+    if is_revoked(revocation_store.as_ref(), &claims) {
+        return ApiError::InvalidToken.into_response();
+    }
+
+    if let Ok(Some(user)) = get_user_by_username(&db.inner(), &claims.sub.clone().into()).await {
+        if user.deactivated_at.is_some() {
+            return ApiError::AccountDeactivated.into_response();
+        }
+
+        request.extensions_mut().insert(user);
+        request.extensions_mut().insert(claims);
+        let response = next.run(request).await;
+        return response;
+    }
+
+    ApiError::InvalidToken.into_response()
+}
+
+/// Lets the request through only for admins. Layered inside `check_auth`,
+/// which has already put the caller's `User` in the extensions.
+pub async fn require_admin<B>(request: Request<B>, next: Next<B>) -> Response {
+    match request.extensions().get::<User>() {
+        Some(user) if user.role == Role::Admin => next.run(request).await,
+        Some(_) => ApiError::Forbidden.into_response(),
+        None => ApiError::InvalidToken.into_response(),
+    }
+}
+
+pub async fn logout(
+    State(state): State<Arc<AppState>>,
+    Extension(claims): Extension<Claims>,
+) -> StatusCode {
+    tracing::info!("logging out user >>> {}", claims.sub);
+    let store = state.get_revocation_store();
+    store.revoke(&claims.jti, claims.exp as u64);
+    if !claims.sid.is_empty() {
+        // the session's refresh token outlives this access token, so the
+        // session is revoked for as long as a refresh token can be valid
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        store.revoke(&claims.sid, now + state.config.jwt.refresh_exp);
+    }
+    StatusCode::NO_CONTENT
+}
+
+pub async fn auth_status(Extension(claims): Extension<Claims>) -> Json<AuthStatusResponse> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    Json(AuthStatusResponse::at(&claims, now))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        app::TestApp,
+        config::test_config,
+        domain::{
+            events::EventEnvelope,
+            fields::{CasePolicy, TokenType},
+        },
+        repository::{backfill_referral_milestones, count_users, deactivate_user},
+    };
+    use axum::http::Method;
+    use serde_json::json;
+    use tokio::sync::broadcast;
+
+    #[test]
+    fn strict_bearer_accepts_only_the_exact_form() {
+        assert_eq!(bearer_token("Bearer abc.def", true), Some("abc.def"));
+        assert_eq!(bearer_token("bearer abc.def", true), None);
+        assert_eq!(bearer_token(" Bearer abc.def", true), None);
+        assert_eq!(bearer_token("Bearer ", true), None);
+        assert_eq!(bearer_token("Basic abc.def", true), None);
+    }
+
+    #[test]
+    fn lenient_bearer_ignores_case_and_whitespace() {
+        assert_eq!(bearer_token("Bearer abc.def", false), Some("abc.def"));
+        assert_eq!(bearer_token("bEaReR abc.def", false), Some("abc.def"));
+        assert_eq!(
+            bearer_token("  Bearer \t abc.def  ", false),
+            Some("abc.def")
+        );
+        assert_eq!(bearer_token("Bearer", false), None);
+        assert_eq!(bearer_token("Bearer   ", false), None);
+        assert_eq!(bearer_token("Basic abc.def", false), None);
+    }
+
+    fn request(username: &str, invitation_code: Option<&str>) -> AuthenticateRequest {
+        AuthenticateRequest {
+            username: username.to_owned(),
+            invitation_code: invitation_code.map(str::to_owned),
+        }
+    }
+
+    #[test]
+    fn idempotency_keys_are_scoped_per_user() {
+        let alice = request("alice", None);
+        let bob = request("bob", None);
+        assert_ne!(alice.idempotency_scope("key"), bob.idempotency_scope("key"));
+        assert_eq!(
+            alice.idempotency_scope("key"),
+            request(" Alice ", None).idempotency_scope("key")
+        );
+    }
+
+    #[test]
+    fn fingerprints_are_stable_and_cover_the_whole_payload() {
+        let fingerprint = request("alice", Some("BOB-1234")).fingerprint();
+        assert_eq!(
+            fingerprint,
+            request("alice", Some("BOB-1234")).fingerprint()
+        );
+        assert_ne!(fingerprint, request("alice", None).fingerprint());
+        assert_ne!(
+            fingerprint,
+            request("alice", Some("BOB-9999")).fingerprint()
+        );
+    }
+
+    #[sqlx::test]
+    async fn refreshing_is_refused_for_a_deactivated_account(pool: sqlx::PgPool) {
+        let app = TestApp::new(test_config(), pool.clone());
+        let tokens = app.authenticate("alice", None).await;
+        let refresh = json!({ "refreshToken": tokens["refreshToken"] });
+
+        let (status, _) = app
+            .call(Method::POST, "/token/refresh", None, Some(refresh.clone()))
+            .await;
+        assert_eq!(status, StatusCode::OK);
+
+        // deactivated without going through the route, so nothing is revoked
+        deactivate_user(&pool, &"alice".to_owned().into())
+            .await
+            .unwrap();
+        let (status, body) = app
+            .call(Method::POST, "/token/refresh", None, Some(refresh))
+            .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body["code"], "ACCOUNT_DEACTIVATED");
+    }
+
+    #[sqlx::test]
+    async fn registration_fails_once_every_invite_code_is_taken(pool: sqlx::PgPool) {
+        let mut config = test_config();
+        config.invite_code.suffix_length = 4;
+        config.invite_code.scale_with_user_count = false;
+        // every code "ali" + four digits can generate
+        sqlx::query(
+            "insert into users (uid, username, invite_code)
+             select gen_random_uuid(), 'ali' || n, 'ali' || n
+             from generate_series(1000, 9999) as n",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let app = TestApp::new(config, pool.clone());
+
+        let (status, body) = app
+            .call(
+                Method::POST,
+                "/authenticate",
+                None,
+                Some(json!({ "username": "alice" })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(body["code"], "INVITE_CODE_EXHAUSTED");
+        assert!(get_user_by_username(&pool, &"alice".to_owned().into())
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[sqlx::test]
+    async fn creating_a_taken_username_is_a_conflict(pool: sqlx::PgPool) {
+        let app = TestApp::new(test_config(), pool);
+        create_user(&app.state, &"alice".to_owned().into(), None)
+            .await
+            .unwrap();
+
+        let result = create_user(&app.state, &"Alice".to_owned().into(), None).await;
+        assert!(matches!(result, Err(DatabaseError::Conflict)));
+    }
+
+    #[sqlx::test]
+    async fn concurrent_registrations_of_a_username_all_log_in(pool: sqlx::PgPool) {
+        let app = TestApp::new(test_config(), pool.clone());
+
+        let responses = futures::future::join_all((0..8).map(|_| {
+            app.call(
+                Method::POST,
+                "/authenticate",
+                None,
+                Some(json!({ "username": "alice" })),
+            )
+        }))
+        .await;
+        for (status, body) in responses {
+            assert_eq!(status, StatusCode::OK, "{body}");
+            assert!(body["token"].is_string());
+        }
+        assert_eq!(count_users(&pool).await.unwrap(), 1);
+    }
+
+    /// Milestones published since the last call.
+    fn reached(rx: &mut broadcast::Receiver<EventEnvelope>) -> Vec<i64> {
+        let mut reached = Vec::new();
+        while let Ok(envelope) = rx.try_recv() {
+            if let AppEvent::ReferralMilestone(event) = envelope.event {
+                reached.push(event.total);
+            }
+        }
+        reached
+    }
+
+    async fn invite_code(pool: &sqlx::PgPool, username: &str) -> String {
+        get_user_by_username(pool, &username.to_owned().into())
+            .await
+            .unwrap()
+            .unwrap()
+            .invite_code
+            .inner()
+    }
+
+    #[sqlx::test]
+    async fn each_milestone_fires_exactly_once(pool: sqlx::PgPool) {
+        let mut config = test_config();
+        config.referral.milestones = vec![1, 2];
+        let app = TestApp::new(config, pool.clone());
+        app.authenticate("alice", None).await;
+        let code = invite_code(&pool, "alice").await;
+        let (mut rx, _) = app.state.subscribe(None);
+
+        for (username, expected) in [("bob", vec![1]), ("carol", vec![2]), ("dave", vec![])] {
+            app.authenticate(username, Some(&code)).await;
+            assert_eq!(reached(&mut rx), expected, "{username}");
+        }
+
+        publish_milestones(&app.state, &"alice".to_owned().into()).await;
+        assert!(reached(&mut rx).is_empty());
+    }
+
+    #[sqlx::test]
+    async fn milestones_already_passed_are_backfilled_silently(pool: sqlx::PgPool) {
+        let mut config = test_config();
+        config.referral.milestones = vec![1, 2, 4];
+        let alice: Username = "alice".to_owned().into();
+        let code = InviteCode::new("alice", 6, CasePolicy::Preserve);
+        create_new_user(&pool, &alice, &code, None).await.unwrap();
+        for username in ["bob", "carol"] {
+            let code = InviteCode::new(username, 6, CasePolicy::Preserve);
+            create_new_user(
+                &pool,
+                &username.to_owned().into(),
+                &code,
+                Some(alice.clone()),
+            )
+            .await
+            .unwrap();
+        }
+
+        let backfilled = backfill_referral_milestones(&pool, &config.referral.milestones)
+            .await
+            .unwrap();
+        assert_eq!(backfilled, 2);
+
+        let app = TestApp::new(config, pool.clone());
+        let (mut rx, _) = app.state.subscribe(None);
+        app.authenticate("dave", Some(&code.inner())).await;
+        assert!(reached(&mut rx).is_empty());
+        app.authenticate("erin", Some(&code.inner())).await;
+        assert_eq!(reached(&mut rx), [4]);
+    }
+
+    #[sqlx::test]
+    async fn admin_routes_are_only_open_to_admins(pool: sqlx::PgPool) {
+        let app = TestApp::new(test_config(), pool.clone());
+        for username in ["alice", "bob"] {
+            let code = InviteCode::new(username, 6, CasePolicy::Preserve);
+            create_new_user(&pool, &username.to_owned().into(), &code, None)
+                .await
+                .unwrap();
         }
+        sqlx::query("update users set role = 'admin' where username = 'alice'")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let sign = |username: &str| {
+            generate_auth_token(
+                &username.to_owned().into(),
+                "session",
+                &app.state.config.jwt,
+                &app.state.get_jwt_keys(),
+            )
+            .unwrap()
+        };
+
+        let (status, body) = app
+            .call(Method::GET, "/admin/users", Some(&sign("bob")), None)
+            .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body["code"], "FORBIDDEN");
+
+        let (status, _) = app
+            .call(Method::GET, "/admin/users", Some(&sign("alice")), None)
+            .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, _) = app.call(Method::GET, "/admin/users", None, None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
     }
 
-    (StatusCode::UNAUTHORIZED).into_response()
+    #[test]
+    fn status_counts_down_to_expiry() {
+        let claims = Claims {
+            jti: "jti".to_owned(),
+            sub: "alice".to_owned(),
+            iss: "killpowa".to_owned(),
+            iat: 1_000,
+            exp: 4_600,
+            token_type: TokenType::Access,
+            sid: "sid".to_owned(),
+        };
+
+        let status = AuthStatusResponse::at(&claims, 1_000);
+        assert!(status.valid);
+        assert_eq!(status.expires_in_secs, 3_600);
+        assert_eq!(AuthStatusResponse::at(&claims, 4_599).expires_in_secs, 1);
+        assert_eq!(AuthStatusResponse::at(&claims, 4_600).expires_in_secs, 0);
+        // accepted within the leeway, never negative
+        assert_eq!(AuthStatusResponse::at(&claims, 4_630).expires_in_secs, 0);
+    }
 }
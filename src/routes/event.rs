@@ -1,36 +1,364 @@
-use crate::app::AppState;
+use crate::{
+    app::{AppState, StreamStats},
+    domain::{
+        events::{EventEnvelope, Replay},
+        fields::{User, Username},
+        timestamp::{self, TimestampFormat},
+    },
+};
 use async_stream::try_stream;
 use axum::{
-    extract::State,
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        State,
+    },
+    http::HeaderMap,
     response::{
         sse::{Event, KeepAlive},
-        Sse,
+        Response, Sse,
     },
+    Extension,
 };
 use futures::Stream;
-use std::{convert::Infallible, sync::Arc};
+use serde::Serialize;
+use std::{convert::Infallible, sync::Arc, time::Duration};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+/// Pings websocket clients at the same rate SSE keep-alives are sent.
+#[cfg(not(test))]
+const WS_PING_INTERVAL: Duration = Duration::from_secs(15);
+#[cfg(test)]
+const WS_PING_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Serializes an event for `username`, or `None` if it isn't meant for them
+/// or can't be serialized. Shared by the SSE and websocket streams.
+fn serialize_event(
+    envelope: &EventEnvelope,
+    username: &Username,
+    stats: &StreamStats,
+    format: TimestampFormat,
+) -> Option<String> {
+    if !envelope.event.is_relevant_to(username) {
+        return None;
+    }
+
+    serialize_or_skip(&envelope.event, envelope.event.kind(), stats, format)
+}
+
+/// `None` when `value` fails to serialize, so the caller skips it rather
+/// than tearing down the stream. The failure is counted and logged.
+fn serialize_or_skip<T: Serialize>(
+    value: &T,
+    kind: &str,
+    stats: &StreamStats,
+    format: TimestampFormat,
+) -> Option<String> {
+    match timestamp::with_format(format, || serde_json::to_string(value)) {
+        Ok(data) => Some(data),
+        Err(e) => {
+            let failures = stats.record_serialization_failure();
+            tracing::error!(
+                error = ?e,
+                kind,
+                failures,
+                "Failed to serialize event, skipping"
+            );
+            None
+        }
+    }
+}
+
+/// The next event for a stream. A subscriber that fell behind skips ahead
+/// to the oldest event still buffered, the ones it missed are counted as
+/// lagged. `None` once the sender is gone, i.e. the app is shutting down.
+async fn next_event(
+    rx: &mut broadcast::Receiver<EventEnvelope>,
+    stats: &StreamStats,
+    stream: &str,
+    username: &Username,
+) -> Option<EventEnvelope> {
+    loop {
+        match rx.recv().await {
+            Ok(envelope) => return Some(envelope),
+            Err(RecvError::Lagged(skipped)) => {
+                let lagged = stats.record_lagged_events(skipped);
+                tracing::warn!(
+                    skipped,
+                    lagged,
+                    "{} subscriber {} lagged behind, events were dropped",
+                    stream,
+                    username
+                );
+            }
+            Err(RecvError::Closed) => return None,
+        }
+    }
+}
+
+/// Turns an event into an SSE frame for `username`.
+fn to_sse_event(
+    envelope: &EventEnvelope,
+    username: &Username,
+    stats: &StreamStats,
+    format: TimestampFormat,
+) -> Option<Event> {
+    serialize_event(envelope, username, stats, format)
+        .map(|data| Event::default().id(envelope.id.to_string()).data(data))
+}
 
 pub async fn stream(
     State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    headers: HeaderMap,
 ) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
-    tracing::info!("new connection to sse stream >>>");
+    let last_event_id = headers
+        .get("last-event-id")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok());
 
-    let mut rx = state.get_sender().subscribe();
+    tracing::info!(
+        "new connection to sse stream >>> {} last event id >>> {:?}",
+        user.username,
+        last_event_id
+    );
+
+    let (mut rx, replay) = state.subscribe(last_event_id);
+    let stats = state.get_stream_stats();
+    let format = state.config.application.timestamp_format;
+    let mut shutdown = state.get_shutdown();
+    let subscriber = state.get_metrics().track_subscriber();
 
     Sse::new(try_stream! {
-        loop {
-            match rx.recv().await {
-                Ok(i) => {
-                    let event = Event::default().data(serde_json::to_string(&i).unwrap());
+        // dropped along with the stream when the client goes away
+        let _subscriber = subscriber;
 
-                    yield event;
+        match replay {
+            Replay::Events(missed) => {
+                for envelope in missed {
+                    if let Some(event) = to_sse_event(&envelope, &user.username, &stats, format) {
+                        yield event;
+                    }
                 }
+            }
+
+            // the gap is no longer buffered, tell the client to refetch
+            Replay::Resync(latest) => {
+                yield Event::default()
+                    .id(latest.to_string())
+                    .data(r#"{"type":"Resync"}"#);
+            }
+        }
 
-                Err(e) => {
-                    tracing::error!(error = ?e, "Failed to get");
+        loop {
+            let envelope = tokio::select! {
+                envelope = next_event(&mut rx, &stats, "sse", &user.username) => match envelope {
+                    Some(envelope) => envelope,
+                    None => {
+                        tracing::info!("event channel closed, ending sse stream >>> {}", user.username);
+                        break;
+                    }
+                },
+                _ = shutdown.changed() => {
+                    tracing::info!("server shutting down, ending sse stream >>> {}", user.username);
+                    break;
                 }
+            };
+
+            if let Some(event) = to_sse_event(&envelope, &user.username, &stats, format) {
+                yield event;
             }
         }
     })
     .keep_alive(KeepAlive::default())
 }
+
+/// The event stream over a websocket, for clients without good EventSource
+/// support. Each event is sent as a JSON text frame, filtered like `/stream`.
+pub async fn ws(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    tracing::info!("new connection to websocket >>> {}", user.username);
+    upgrade.on_upgrade(move |socket| push_events(socket, state, user.username))
+}
+
+async fn push_events(mut socket: WebSocket, state: Arc<AppState>, username: Username) {
+    // websocket clients can't resume, so there's nothing to replay
+    let (mut rx, _) = state.subscribe(None);
+    let stats = state.get_stream_stats();
+    let format = state.config.application.timestamp_format;
+    let mut shutdown = state.get_shutdown();
+    let _subscriber = state.get_metrics().track_subscriber();
+    let mut ping = tokio::time::interval(WS_PING_INTERVAL);
+
+    loop {
+        let sent = tokio::select! {
+            envelope = next_event(&mut rx, &stats, "websocket", &username) => match envelope {
+                Some(envelope) => match serialize_event(&envelope, &username, &stats, format) {
+                    Some(data) => socket.send(Message::Text(data)).await,
+                    None => Ok(()),
+                },
+                None => {
+                    let _ = socket.send(Message::Close(None)).await;
+                    break;
+                }
+            },
+
+            // pings from the client are answered by the websocket layer, we
+            // only need to notice when it closes
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => Ok(()),
+            },
+
+            _ = ping.tick() => socket.send(Message::Ping(Vec::new())).await,
+
+            _ = shutdown.changed() => {
+                tracing::info!("server shutting down, closing websocket >>> {}", username);
+                let _ = socket.send(Message::Close(None)).await;
+                break;
+            }
+        };
+
+        if sent.is_err() {
+            break;
+        }
+    }
+
+    tracing::info!("websocket closed >>> {}", username);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        app::TestApp,
+        config::test_config,
+        domain::events::{AppEvent, NewReferralEvent},
+        repository::get_user_by_username,
+    };
+
+    fn referral(id: u64) -> EventEnvelope {
+        EventEnvelope {
+            id,
+            event: AppEvent::NewReferral(NewReferralEvent {
+                referrer: "alice".to_owned().into(),
+                referred_user: format!("user{id}").into(),
+            }),
+        }
+    }
+
+    #[tokio::test]
+    async fn a_lagging_subscriber_skips_ahead_and_keeps_streaming() {
+        let (tx, mut rx) = broadcast::channel(2);
+        let stats = StreamStats::new().unwrap();
+        let alice = "alice".to_owned().into();
+        for id in 1..=5 {
+            assert!(tx.send(referral(id)).is_ok());
+        }
+
+        // only the last two fit in the channel
+        let envelope = next_event(&mut rx, &stats, "test", &alice).await.unwrap();
+        assert_eq!(envelope.id, 4);
+        assert_eq!(stats.lagged_events(), 3);
+
+        let envelope = next_event(&mut rx, &stats, "test", &alice).await.unwrap();
+        assert_eq!(envelope.id, 5);
+        assert!(tx.send(referral(6)).is_ok());
+        let envelope = next_event(&mut rx, &stats, "test", &alice).await.unwrap();
+        assert_eq!(envelope.id, 6);
+        assert_eq!(stats.lagged_events(), 3);
+    }
+
+    #[tokio::test]
+    async fn a_closed_channel_ends_the_stream() {
+        let (tx, mut rx) = broadcast::channel(2);
+        let stats = StreamStats::new().unwrap();
+        let alice = "alice".to_owned().into();
+        assert!(tx.send(referral(1)).is_ok());
+        drop(tx);
+
+        // what was already sent is still delivered
+        let envelope = next_event(&mut rx, &stats, "test", &alice).await.unwrap();
+        assert_eq!(envelope.id, 1);
+        assert!(next_event(&mut rx, &stats, "test", &alice).await.is_none());
+    }
+
+    struct Unserializable;
+
+    impl Serialize for Unserializable {
+        fn serialize<S: serde::Serializer>(&self, _: S) -> Result<S::Ok, S::Error> {
+            Err(serde::ser::Error::custom("unserializable"))
+        }
+    }
+
+    #[test]
+    fn a_serialization_failure_is_skipped_and_counted() {
+        let stats = StreamStats::new().unwrap();
+        let alice = "alice".to_owned().into();
+
+        let skipped = serialize_or_skip(
+            &Unserializable,
+            "Unserializable",
+            &stats,
+            TimestampFormat::Rfc3339,
+        );
+        assert!(skipped.is_none());
+        assert_eq!(stats.serialization_failures(), 1);
+
+        // the next event still goes out
+        let event = to_sse_event(&referral(2), &alice, &stats, TimestampFormat::Rfc3339);
+        assert!(event.is_some());
+        assert_eq!(stats.serialization_failures(), 1);
+    }
+
+    #[sqlx::test]
+    async fn websocket_clients_get_their_events_and_pings(pool: sqlx::PgPool) {
+        use futures::StreamExt;
+        use tokio_tungstenite::tungstenite::{client::IntoClientRequest, Message};
+
+        let app = TestApp::new(test_config(), pool.clone());
+        let token = app.authenticate("alice", None).await["token"]
+            .as_str()
+            .unwrap()
+            .to_owned();
+        let addr = app.serve();
+
+        let mut request = format!("ws://{addr}/ws").into_client_request().unwrap();
+        request.headers_mut().insert(
+            axum::http::header::AUTHORIZATION,
+            format!("Bearer {token}").parse().unwrap(),
+        );
+        let (mut socket, response) = tokio_tungstenite::connect_async(request).await.unwrap();
+        assert_eq!(response.status(), 101);
+
+        let code = get_user_by_username(&pool, &"alice".to_owned().into())
+            .await
+            .unwrap()
+            .unwrap()
+            .invite_code
+            .inner();
+        app.authenticate("bob", Some(&code)).await;
+
+        let (mut referral, mut pinged) = (None, false);
+        while referral.is_none() || !pinged {
+            let message = tokio::time::timeout(Duration::from_secs(5), socket.next())
+                .await
+                .expect("no message within 5s")
+                .unwrap()
+                .unwrap();
+            match message {
+                Message::Ping(_) => pinged = true,
+                Message::Text(text) => {
+                    let event: serde_json::Value = serde_json::from_str(&text).unwrap();
+                    if event["type"] == "NewReferral" {
+                        referral = Some(event);
+                    }
+                }
+                other => panic!("unexpected message {other:?}"),
+            }
+        }
+        assert_eq!(referral.unwrap()["data"]["referred_user"], "bob");
+    }
+}
@@ -1,5 +1,12 @@
-use axum::Json;
+use crate::app::AppState;
+use axum::{
+    extract::State,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
 use serde_json::{json, Value};
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
 
 pub async fn health() -> Json<Value> {
     Json(json!( {
@@ -7,6 +14,85 @@ pub async fn health() -> Json<Value> {
     }))
 }
 
+/// Readiness probe: unlike `health` this checks the database is reachable.
+pub async fn ready(State(state): State<Arc<AppState>>) -> (StatusCode, Json<Value>) {
+    let pool = state.get_pool();
+    let ping = tokio::time::timeout(
+        Duration::from_secs(2),
+        sqlx::query("select 1").execute(&pool),
+    )
+    .await;
+
+    match ping {
+        Ok(Ok(_)) => {
+            return (
+                StatusCode::OK,
+                Json(json!({
+                    "message": "API ready!",
+                })),
+            )
+        }
+        // the driver error can name hosts and users, so it only goes to the logs
+        Ok(Err(e)) => tracing::error!("readiness check failed >>> {}", e),
+        Err(_) => tracing::error!("readiness check failed >>> timed out"),
+    }
+
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        Json(json!({
+            "message": "Database unreachable",
+        })),
+    )
+}
+
+/// Optional behaviour clients may want to adapt to, see
+/// `Config::capabilities`. Maintenance mode is reported as currently toggled.
+pub async fn capabilities(
+    State(state): State<Arc<AppState>>,
+) -> Json<BTreeMap<&'static str, bool>> {
+    let mut capabilities = state.config.capabilities();
+    capabilities.insert("maintenanceMode", state.in_maintenance());
+    Json(capabilities)
+}
+
+/// Prometheus scrape endpoint.
+pub async fn metrics(State(state): State<Arc<AppState>>) -> Response {
+    match state.get_metrics().render() {
+        Ok(body) => ([(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)], body).into_response(),
+        Err(e) => {
+            tracing::error!("rendering metrics failed >>> {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+pub mod admin;
 pub mod auth;
 pub mod event;
 pub mod user;
+
+#[cfg(test)]
+mod tests {
+    use crate::{app::TestApp, config::test_config};
+    use axum::http::{Method, StatusCode};
+    use sqlx::postgres::PgPoolOptions;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn ready_fails_without_a_database_while_health_stays_up() {
+        // nothing listens on port 1, connecting fails as soon as it's tried
+        let pool = PgPoolOptions::new()
+            .acquire_timeout(Duration::from_millis(500))
+            .connect_lazy("postgres://postgres@127.0.0.1:1/unreachable")
+            .unwrap();
+        let app = TestApp::new(test_config(), pool);
+
+        let (status, body) = app.call(Method::GET, "/health", None, None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["message"], "API up!");
+
+        let (status, body) = app.call(Method::GET, "/ready", None, None).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["message"], "Database unreachable");
+    }
+}
@@ -1,25 +1,133 @@
-use std::sync::Arc;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
 
 use crate::{
     app::AppState,
-    domain::{errors::ApiError, fields::User},
-    repository::{fetch_users, FetchUserQuery},
+    config::DeactivatedReferrer,
+    domain::{
+        errors::ApiError,
+        fields::{Claims, InviteCode, User, Username},
+        timestamp,
+    },
+    repository::{
+        deactivate_user, delete_user, fetch_leaderboard, fetch_login_events, fetch_referral_tree,
+        fetch_referrals, fetch_users, get_cohort_rank, get_users_by_usernames, FetchUserQuery,
+        OrderBy, UserCursor,
+    },
+    routes::auth::publish_milestones,
 };
 use axum::{
-    extract::{Query, State},
+    extract::{rejection::QueryRejection, Query, State},
+    http::StatusCode,
     Extension, Json,
 };
 use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use time::OffsetDateTime;
+use url::Url;
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Expand {
+    Referrer,
+}
 
+/// The parts of a referrer that are shown to other users; their invite code
+/// is deliberately left out.
 #[derive(Serialize)]
-pub struct AuthenticatedUserResponse {
+#[serde(rename_all = "camelCase")]
+pub struct ReferrerInfo {
+    username: Username,
+    referrals: i64,
+    #[serde(serialize_with = "timestamp::serialize")]
+    joined_at: OffsetDateTime,
+}
+
+impl From<User> for ReferrerInfo {
+    fn from(value: User) -> Self {
+        Self {
+            username: value.username,
+            referrals: value.referrals,
+            joined_at: value.joined_at,
+        }
+    }
+}
+
+/// What's left of a deactivated referrer when `referral.deactivated_referrer`
+/// is `flag`.
+#[derive(Serialize)]
+pub struct DeactivatedUser {
+    username: Username,
+    deleted: bool,
+}
+
+impl DeactivatedUser {
+    fn new(username: Username) -> Self {
+        Self {
+            username,
+            deleted: true,
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum ReferrerView {
+    Active(ReferrerInfo),
+    Deactivated(DeactivatedUser),
+}
+
+#[derive(Serialize)]
+pub struct UserView {
     #[serde(flatten)]
     user: User,
+    /// Only present when `expand=referrer` was asked for, and `null` for
+    /// users nobody referred.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    referrer: Option<Option<ReferrerView>>,
+}
+
+#[derive(Serialize)]
+pub struct AuthenticatedUserResponse {
+    #[serde(flatten)]
+    user: UserView,
+}
+
+#[derive(Deserialize)]
+pub struct MeParams {
+    expand: Option<Expand>,
 }
 
 #[derive(Deserialize)]
 pub struct QueryParams {
     username: Option<String>,
+    invite_code: Option<String>,
+    referred_by: Option<String>,
+    page: Option<i64>,
+    limit: Option<i64>,
+    with_total: Option<bool>,
+    sort: Option<OrderBy>,
+    expand: Option<Expand>,
+    cursor: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct PageParams {
+    page: Option<i64>,
+    limit: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct TreeParams {
+    #[serde(alias = "depth")]
+    max_depth: Option<i32>,
+    max_children_per_node: Option<usize>,
+}
+
+#[derive(Deserialize)]
+pub struct LeaderboardParams {
     page: Option<i64>,
     limit: Option<i64>,
 }
@@ -30,49 +138,1223 @@ pub struct Pagination {
     has_next: bool,
     has_prev: bool,
     current_page: i64,
-    total_pages: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total_pages: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total_items: Option<i64>,
+    /// Pass back as `cursor` to get the next page; more reliable than `page`
+    /// while users are signing up. Only given when sorting by join date.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_cursor: Option<String>,
+}
+
+impl Pagination {
+    /// Normalizes the requested page and limit to `page >= 1` and
+    /// `1 <= limit <= max_limit`, and returns them with the row offset.
+    pub(crate) fn bounds(page: Option<i64>, limit: Option<i64>, max_limit: i64) -> (i64, i64, i64) {
+        let page = page.unwrap_or(1).max(1);
+        let limit = limit.unwrap_or(10).clamp(1, max_limit);
+        (page, limit, (page - 1).saturating_mul(limit))
+    }
+
+    pub(crate) fn from_count(page: i64, limit: i64, count: i64) -> Self {
+        // an empty listing still reports a single (empty) page
+        let total_pages = ((count + limit - 1) / limit).max(1);
+        Self {
+            has_next: page < total_pages,
+            has_prev: page > 1,
+            current_page: page,
+            total_pages: Some(total_pages),
+            total_items: Some(count),
+            next_cursor: None,
+        }
+    }
 }
 
 #[derive(Serialize)]
 pub struct GetUsersResponse {
+    users: Vec<UserView>,
+    #[serde(flatten)]
+    pagination: Pagination,
+}
+
+#[derive(Serialize)]
+pub struct ReferralsResponse {
     users: Vec<User>,
     #[serde(flatten)]
     pagination: Pagination,
 }
 
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum ReferralNodeUser {
+    Active(User),
+    Deactivated(DeactivatedUser),
+}
+
+#[derive(Serialize)]
+pub struct ReferralNode {
+    #[serde(flatten)]
+    user: ReferralNodeUser,
+    referred: Vec<ReferralNode>,
+    /// Referrals left out of `referred` past `max_children_per_node`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    more: Option<usize>,
+    /// Set on nodes at `max_depth` that referred others, who weren't fetched.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    truncated: bool,
+}
+
+#[derive(Serialize)]
+pub struct ReferralTreeResponse {
+    depth: i32,
+    users: Vec<ReferralNode>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    more: Option<usize>,
+}
+
+/// How much of a downline `build_referral_tree` lays out.
+#[derive(Clone, Copy)]
+struct TreeShape {
+    max_depth: i32,
+    max_children: usize,
+    deactivated_referrer: DeactivatedReferrer,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LoginEntry {
+    #[serde(serialize_with = "timestamp::serialize")]
+    logged_in_at: OffsetDateTime,
+    ip_address: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct LoginsResponse {
+    logins: Vec<LoginEntry>,
+    #[serde(flatten)]
+    pagination: Pagination,
+}
+
+#[derive(Serialize)]
+pub struct LeaderboardEntry {
+    rank: i64,
+    #[serde(flatten)]
+    user: User,
+}
+
+#[derive(Serialize)]
+pub struct LeaderboardResponse {
+    users: Vec<LeaderboardEntry>,
+    #[serde(flatten)]
+    pagination: Pagination,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CohortRankResponse {
+    rank: i64,
+    cohort_size: i64,
+    #[serde(serialize_with = "timestamp::serialize")]
+    cohort_start: OffsetDateTime,
+}
+
+#[derive(Deserialize)]
+pub struct BatchUsersRequest {
+    usernames: Vec<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BatchUsersResponse {
+    users: Vec<User>,
+    not_found: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct ShareLinkResponse {
+    url: String,
+}
+
+/// Wraps `users` for the response, looking up all their referrers in one
+/// query when `expand=referrer` was asked for.
+async fn to_user_views(
+    pool: &PgPool,
+    users: Vec<User>,
+    expand: Option<Expand>,
+    deactivated_referrer: DeactivatedReferrer,
+) -> Result<Vec<UserView>, ApiError> {
+    if expand != Some(Expand::Referrer) {
+        return Ok(users
+            .into_iter()
+            .map(|user| UserView {
+                user,
+                referrer: None,
+            })
+            .collect());
+    }
+
+    let referrer_names: Vec<String> = users
+        .iter()
+        .filter_map(|u| u.referred_by.as_ref().map(|r| r.inner()))
+        .collect();
+    let referrers: HashMap<String, User> = if referrer_names.is_empty() {
+        HashMap::new()
+    } else {
+        get_users_by_usernames(pool, &referrer_names)
+            .await?
+            .into_iter()
+            .map(|r| (r.username.inner(), r))
+            .collect()
+    };
+
+    Ok(users
+        .into_iter()
+        .map(|user| {
+            let referrer = referrer_view(&user, &referrers, deactivated_referrer);
+            UserView {
+                user,
+                referrer: Some(referrer),
+            }
+        })
+        .collect())
+}
+
+/// `user`'s referrer out of the active `referrers` that were looked up. A
+/// referrer that's set but wasn't found is deactivated, since deleting an
+/// account hands its referrals up to its own referrer.
+fn referrer_view(
+    user: &User,
+    referrers: &HashMap<String, User>,
+    deactivated_referrer: DeactivatedReferrer,
+) -> Option<ReferrerView> {
+    let referred_by = user.referred_by.as_ref()?;
+    match referrers.get(&referred_by.inner()) {
+        // several users on a page can share a referrer, so clone rather
+        // than take it out of the map
+        Some(referrer) => Some(ReferrerView::Active(referrer.clone().into())),
+        None => match deactivated_referrer {
+            DeactivatedReferrer::Flag => Some(ReferrerView::Deactivated(DeactivatedUser::new(
+                referred_by.clone(),
+            ))),
+            DeactivatedReferrer::Hide => None,
+        },
+    }
+}
+
 pub async fn get_authenticated_user(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<MeParams>,
     Extension(user): Extension<User>,
 ) -> Result<Json<AuthenticatedUserResponse>, ApiError> {
+    let pool = state.get_pool();
+    let user = to_user_views(
+        &pool,
+        vec![user],
+        params.expand,
+        state.config.referral.deactivated_referrer,
+    )
+    .await?
+    .pop()
+    .ok_or(ApiError::ServerError)?;
     Ok(Json(AuthenticatedUserResponse { user }))
 }
 
+pub async fn delete_authenticated_user(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Extension(claims): Extension<Claims>,
+) -> Result<StatusCode, ApiError> {
+    tracing::info!("deleting user >>> {}", user.username);
+    let pool = state.get_pool();
+    delete_user(&pool, &user.username).await?;
+    // the account is gone, so is every session it had
+    state.revoke_all_tokens(&claims.sub);
+    // their referrals were handed up to their own referrer
+    if let Some(referrer) = &user.referred_by {
+        publish_milestones(&state, referrer).await;
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn deactivate_authenticated_user(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+    Extension(claims): Extension<Claims>,
+) -> Result<StatusCode, ApiError> {
+    tracing::info!("deactivating user >>> {}", user.username);
+    let pool = state.get_pool();
+    deactivate_user(&pool, &user.username).await?;
+    // signs out every session, not just the one that asked
+    state.revoke_all_tokens(&claims.sub);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn get_share_link(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+) -> Json<ShareLinkResponse> {
+    let url = share_link(
+        &state.config.application.public_base_url,
+        &user.invite_code.inner(),
+    );
+    Json(ShareLinkResponse { url: url.into() })
+}
+
+/// `base_url` with the invite code added as `ref`, keeping whatever path and
+/// query the base already has.
+fn share_link(base_url: &Url, invite_code: &str) -> Url {
+    let mut url = base_url.clone();
+    url.query_pairs_mut().append_pair("ref", invite_code);
+    url
+}
+
+pub async fn get_my_cohort_rank(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+) -> Result<Json<CohortRankResponse>, ApiError> {
+    // the caller is always part of their own cohort, a single-member cohort
+    // just ranks them first. A replica can lag behind a fresh registration
+    // though, so fall back to the primary before giving up
+    let cohort = match get_cohort_rank(&state.get_read_pool(), &user.username).await? {
+        Some(cohort) => cohort,
+        None => get_cohort_rank(&state.get_pool(), &user.username)
+            .await?
+            .ok_or(ApiError::NotFound)?,
+    };
+
+    Ok(Json(CohortRankResponse {
+        rank: cohort.rank,
+        cohort_size: cohort.cohort_size,
+        cohort_start: cohort.cohort_start,
+    }))
+}
+
+/// Users the caller referred directly, newest first.
+pub async fn get_my_referrals(
+    State(state): State<Arc<AppState>>,
+    query: Result<Query<PageParams>, QueryRejection>,
+    Extension(user): Extension<User>,
+) -> Result<Json<ReferralsResponse>, ApiError> {
+    let Query(query) = query?;
+    let pool = state.get_read_pool();
+    let (page, limit, skip) = Pagination::bounds(
+        query.page,
+        query.limit,
+        state.config.application.max_page_size,
+    );
+
+    let (users, count) = fetch_referrals(&pool, &user.username, skip, limit).await?;
+    Ok(Json(ReferralsResponse {
+        users,
+        pagination: Pagination::from_count(page, limit, count),
+    }))
+}
+
+/// The caller's login history, most recent first.
+pub async fn get_my_logins(
+    State(state): State<Arc<AppState>>,
+    query: Result<Query<PageParams>, QueryRejection>,
+    Extension(user): Extension<User>,
+) -> Result<Json<LoginsResponse>, ApiError> {
+    let Query(query) = query?;
+    let pool = state.get_read_pool();
+    let (page, limit, skip) = Pagination::bounds(
+        query.page,
+        query.limit,
+        state.config.application.max_page_size,
+    );
+
+    let (events, count) = fetch_login_events(&pool, &user.username, skip, limit).await?;
+    Ok(Json(LoginsResponse {
+        logins: events
+            .into_iter()
+            .map(|e| LoginEntry {
+                logged_in_at: e.logged_in_at,
+                ip_address: e.ip_address,
+            })
+            .collect(),
+        pagination: Pagination::from_count(page, limit, count),
+    }))
+}
+
+/// The caller's downline: their referrals, who those referred, and so on
+/// down to `max_depth` levels, listing at most `max_children_per_node`
+/// referrals under each user.
+pub async fn get_my_referral_tree(
+    State(state): State<Arc<AppState>>,
+    query: Result<Query<TreeParams>, QueryRejection>,
+    Extension(user): Extension<User>,
+) -> Result<Json<ReferralTreeResponse>, ApiError> {
+    let Query(query) = query?;
+    let config = &state.config.referral;
+    let depth = query.max_depth.unwrap_or(config.max_tree_depth);
+    if !(1..=config.max_tree_depth).contains(&depth) {
+        return Err(ApiError::InvalidQuery(format!(
+            "max_depth must be between 1 and {}",
+            config.max_tree_depth
+        )));
+    }
+    let max_children = query
+        .max_children_per_node
+        .unwrap_or(config.max_tree_children);
+    if !(1..=config.max_tree_children).contains(&max_children) {
+        return Err(ApiError::InvalidQuery(format!(
+            "max_children_per_node must be between 1 and {}",
+            config.max_tree_children
+        )));
+    }
+
+    let pool = state.get_read_pool();
+    let nodes = fetch_referral_tree(&pool, &user.username, depth).await?;
+
+    let mut children: HashMap<String, Vec<User>> = HashMap::new();
+    for (_, node) in nodes {
+        if let Some(referrer) = &node.referred_by {
+            children
+                .entry(referrer.as_ref().to_owned())
+                .or_default()
+                .push(node);
+        }
+    }
+
+    let shape = TreeShape {
+        max_depth: depth,
+        max_children,
+        deactivated_referrer: config.deactivated_referrer,
+    };
+    let (users, more) = build_referral_tree(&mut children, user.username.as_ref(), 1, shape);
+    Ok(Json(ReferralTreeResponse { depth, users, more }))
+}
+
+/// Nests `children` under `referrer`, whose referrals sit at `depth`, and
+/// returns at most `shape.max_children` of them along with how many were
+/// left out. Deactivated users only show up as the referrer of someone
+/// still active, and only when the policy flags them.
+fn build_referral_tree(
+    children: &mut HashMap<String, Vec<User>>,
+    referrer: &str,
+    depth: i32,
+    shape: TreeShape,
+) -> (Vec<ReferralNode>, Option<usize>) {
+    // taking the entry out doubles as the visited set
+    let mut nodes: Vec<ReferralNode> = children
+        .remove(referrer)
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|user| {
+            let (referred, more) = if depth < shape.max_depth {
+                build_referral_tree(children, user.username.as_ref(), depth + 1, shape)
+            } else {
+                (vec![], None)
+            };
+            // nothing below max_depth was fetched, only the counts tell
+            // whether the branch goes on
+            let truncated = depth == shape.max_depth && user.referrals > 0;
+
+            let user = if user.deactivated_at.is_none() {
+                ReferralNodeUser::Active(user)
+            } else if shape.deactivated_referrer == DeactivatedReferrer::Flag
+                && (!referred.is_empty() || truncated)
+            {
+                ReferralNodeUser::Deactivated(DeactivatedUser::new(user.username))
+            } else {
+                return None;
+            };
+
+            Some(ReferralNode {
+                user,
+                referred,
+                more,
+                truncated,
+            })
+        })
+        .collect();
+
+    let more = nodes.len().saturating_sub(shape.max_children);
+    nodes.truncate(shape.max_children);
+    (nodes, (more > 0).then_some(more))
+}
+
 pub async fn get_users(
     State(state): State<Arc<AppState>>,
-    Query(query): Query<QueryParams>,
+    query: Result<Query<QueryParams>, QueryRejection>,
     Extension(user): Extension<User>,
 ) -> Result<Json<GetUsersResponse>, ApiError> {
-    let pool = state.get_pool();
-    let page = query.page.unwrap_or(1);
-    let limit = query.limit.unwrap_or(10);
-    let skip = (page - 1) * limit;
+    let Query(query) = query?;
+    let pool = state.get_read_pool();
+    let (page, limit, skip) = Pagination::bounds(
+        query.page,
+        query.limit,
+        state.config.application.max_page_size,
+    );
+    let with_total = query.with_total.unwrap_or(true);
+    let expand = query.expand;
+    let order_by = query.sort.unwrap_or_default();
+    let cursor = query
+        .cursor
+        .as_deref()
+        .map(|c| UserCursor::decode(c).ok_or(ApiError::InvalidCursor))
+        .transpose()?;
+    if cursor.is_some() && order_by != OrderBy::CreatedOn {
+        return Err(ApiError::InvalidCursor);
+    }
+
+    // without a total, or when continuing from a cursor, we over-fetch by one
+    // row to find out if there's a next page
+    let over_fetch = !with_total || cursor.is_some();
+    let invite_code = query
+        .invite_code
+        .map(|code| {
+            InviteCode::parse(
+                code,
+                state.config.invite_code.max_length,
+                state.config.invite_code.case,
+            )
+        })
+        .transpose()
+        .map_err(|_| ApiError::InvalidInviteCode)?;
 
     let query = FetchUserQuery {
         username: query.username,
-        auth_user: user.username.inner(),
-        limit,
+        invite_code,
+        referred_by: query.referred_by.map(|r| r.trim().to_owned()),
+        exclude_user: Some(user.username.inner()),
+        role: None,
+        limit: if over_fetch { limit + 1 } else { limit },
         skip,
+        with_total,
+        order_by,
+        cursor,
+        include_deactivated: false,
     };
+    let by_cursor = query.cursor.is_some();
 
-    let (users, count) = fetch_users(&pool, query).await?;
+    let (mut users, count) = fetch_users(&pool, query).await?;
 
-    let total_pages = (count / limit) + 1;
-    Ok(Json(GetUsersResponse {
+    let mut pagination = match count {
+        Some(count) if !by_cursor => Pagination::from_count(page, limit, count),
+        count => {
+            let has_next = users.len() as i64 > limit;
+            users.truncate(limit as usize);
+            Pagination {
+                has_next,
+                has_prev: by_cursor || page > 1,
+                current_page: page,
+                total_pages: None,
+                total_items: count,
+                next_cursor: None,
+            }
+        }
+    };
+
+    if pagination.has_next && order_by == OrderBy::CreatedOn {
+        pagination.next_cursor = users.last().map(|(cursor, _)| cursor.encode());
+    }
+
+    let users = users.into_iter().map(|(_, user)| user).collect();
+    let users = to_user_views(
+        &pool,
         users,
-        pagination: Pagination {
-            has_next: page < total_pages,
-            has_prev: page > 1,
-            current_page: page,
-            total_pages,
-        },
+        expand,
+        state.config.referral.deactivated_referrer,
+    )
+    .await?;
+    Ok(Json(GetUsersResponse { users, pagination }))
+}
+
+/// Resolves up to `MAX_BATCH_SIZE` usernames at once. Found users come back
+/// in the order they were asked for, the rest are listed in `notFound`.
+pub async fn get_users_batch(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<BatchUsersRequest>,
+) -> Result<Json<BatchUsersResponse>, ApiError> {
+    const MAX_BATCH_SIZE: usize = 100;
+    if payload.usernames.len() > MAX_BATCH_SIZE {
+        return Err(ApiError::BatchTooLarge);
+    }
+
+    let keys: Vec<String> = payload
+        .usernames
+        .iter()
+        .map(|u| u.trim().to_lowercase())
+        .collect();
+    let pool = state.get_read_pool();
+    let found: HashMap<String, User> = get_users_by_usernames(&pool, &keys)
+        .await?
+        .into_iter()
+        .map(|u| (u.username.inner().to_lowercase(), u))
+        .collect();
+
+    let mut seen = HashSet::new();
+    let mut users = vec![];
+    let mut not_found = vec![];
+    for (username, key) in payload.usernames.into_iter().zip(keys) {
+        if !seen.insert(key.clone()) {
+            continue;
+        }
+
+        match found.get(&key) {
+            Some(user) => users.push(user.clone()),
+            None => not_found.push(username),
+        }
+    }
+
+    Ok(Json(BatchUsersResponse { users, not_found }))
+}
+
+pub async fn get_leaderboard(
+    State(state): State<Arc<AppState>>,
+    query: Result<Query<LeaderboardParams>, QueryRejection>,
+) -> Result<Json<LeaderboardResponse>, ApiError> {
+    let Query(query) = query?;
+    let pool = state.get_read_pool();
+    let (page, limit, skip) = Pagination::bounds(
+        query.page,
+        query.limit,
+        state.config.application.max_page_size,
+    );
+
+    let (users, count) = fetch_leaderboard(&pool, skip, limit).await?;
+
+    Ok(Json(LeaderboardResponse {
+        users: users
+            .into_iter()
+            .map(|(rank, user)| LeaderboardEntry { rank, user })
+            .collect(),
+        pagination: Pagination::from_count(page, limit, count),
     }))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        app::TestApp,
+        config::test_config,
+        domain::fields::{CasePolicy, Role},
+        repository::get_user_by_username,
+    };
+    use axum::http::Method;
+    use serde_json::json;
+
+    fn pages(count: i64, page: i64) -> (Option<i64>, bool, bool) {
+        let pagination = Pagination::from_count(page, 10, count);
+        (
+            pagination.total_pages,
+            pagination.has_next,
+            pagination.has_prev,
+        )
+    }
+
+    #[test]
+    fn bounds_clamp_page_and_limit() {
+        assert_eq!(Pagination::bounds(Some(0), Some(10), 100), (1, 10, 0));
+        assert_eq!(Pagination::bounds(Some(-1), Some(10), 100), (1, 10, 0));
+        assert_eq!(Pagination::bounds(Some(2), Some(0), 100), (2, 1, 1));
+        assert_eq!(Pagination::bounds(Some(1), Some(100_000), 100), (1, 100, 0));
+        assert_eq!(Pagination::bounds(Some(3), Some(20), 100), (3, 20, 40));
+        assert_eq!(Pagination::bounds(None, None, 100), (1, 10, 0));
+    }
+
+    #[test]
+    fn bounds_never_overflow_the_offset() {
+        let (_, _, skip) = Pagination::bounds(Some(i64::MAX), Some(100), 100);
+        assert_eq!(skip, i64::MAX);
+    }
+
+    #[test]
+    fn total_pages_rounds_up_partial_pages() {
+        assert_eq!(pages(0, 1), (Some(1), false, false));
+        assert_eq!(pages(19, 1), (Some(2), true, false));
+        assert_eq!(pages(20, 2), (Some(2), false, true));
+        assert_eq!(pages(21, 2), (Some(3), true, true));
+    }
+
+    fn params(uri: &str) -> Result<QueryParams, QueryRejection> {
+        Query::try_from_uri(&uri.parse().unwrap()).map(|Query(params)| params)
+    }
+
+    #[test]
+    fn sort_is_parsed_from_the_query() {
+        assert!(params("/users").unwrap().sort.is_none());
+        assert!(params("/users?sort=created_on").unwrap().sort == Some(OrderBy::CreatedOn));
+        assert!(params("/users?sort=referrals").unwrap().sort == Some(OrderBy::Referrals));
+        assert!(params("/users?sort=Referrals").is_err());
+        assert!(params("/users?sort=rank").is_err());
+    }
+
+    #[test]
+    fn filters_are_parsed_from_the_query() {
+        let filtered = params("/users?invite_code=ALI1234&referred_by=%20bob%20").unwrap();
+        assert_eq!(filtered.invite_code.as_deref(), Some("ALI1234"));
+        assert_eq!(filtered.referred_by.as_deref(), Some(" bob "));
+
+        let unfiltered = params("/users").unwrap();
+        assert!(unfiltered.invite_code.is_none());
+        assert!(unfiltered.referred_by.is_none());
+    }
+
+    fn user(username: &str, referred_by: Option<&str>, deactivated: bool) -> User {
+        User {
+            username: Username::from(username.to_owned()),
+            invite_code: InviteCode::new(username, 4, CasePolicy::Preserve),
+            referred_by: referred_by.map(|r| Username::from(r.to_owned())),
+            referrals: 0,
+            joined_at: OffsetDateTime::UNIX_EPOCH,
+            role: Role::Member,
+            deactivated_at: deactivated.then_some(OffsetDateTime::UNIX_EPOCH),
+        }
+    }
+
+    #[test]
+    fn referrers_serialize_joined_at_in_camel_case() {
+        let json = serde_json::to_value(ReferrerInfo::from(user("alice", None, false))).unwrap();
+        assert_eq!(json["joinedAt"], "1970-01-01T00:00:00Z");
+        assert!(json.get("joined_at").is_none());
+        assert!(json.get("inviteCode").is_none());
+    }
+
+    fn shape(deactivated_referrer: DeactivatedReferrer) -> TreeShape {
+        TreeShape {
+            max_depth: 5,
+            max_children: 50,
+            deactivated_referrer,
+        }
+    }
+
+    fn tree(users: Vec<User>, shape: TreeShape) -> (serde_json::Value, Option<usize>) {
+        let mut children: HashMap<String, Vec<User>> = HashMap::new();
+        for user in users {
+            let referrer = user.referred_by.clone().unwrap().inner();
+            children.entry(referrer).or_default().push(user);
+        }
+        let (nodes, more) = build_referral_tree(&mut children, "root", 1, shape);
+        (serde_json::to_value(nodes).unwrap(), more)
+    }
+
+    #[test]
+    fn deactivated_referrers_follow_the_policy() {
+        let member = user("member", Some("gone"), false);
+        let referrers = HashMap::new();
+
+        let flagged = referrer_view(&member, &referrers, DeactivatedReferrer::Flag);
+        assert_eq!(
+            serde_json::to_value(flagged).unwrap(),
+            serde_json::json!({ "username": "gone", "deleted": true })
+        );
+        assert!(referrer_view(&member, &referrers, DeactivatedReferrer::Hide).is_none());
+    }
+
+    #[test]
+    fn active_referrers_are_shown_in_full() {
+        let member = user("member", Some("alice"), false);
+        let referrers = HashMap::from([("alice".to_owned(), user("alice", None, false))]);
+
+        let referrer = serde_json::to_value(referrer_view(
+            &member,
+            &referrers,
+            DeactivatedReferrer::Hide,
+        ))
+        .unwrap();
+        assert_eq!(referrer["username"], "alice");
+        assert!(referrer.get("deleted").is_none());
+        assert!(referrer_view(
+            &user("alice", None, false),
+            &referrers,
+            DeactivatedReferrer::Flag
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn flagged_tree_keeps_the_downline_of_deactivated_referrers() {
+        let users = vec![
+            user("gone", Some("root"), true),
+            user("leaf", Some("root"), true),
+            user("child", Some("gone"), false),
+        ];
+
+        // the deactivated leaf referred nobody still active, so it's left out
+        let (tree, _) = tree(users, shape(DeactivatedReferrer::Flag));
+        assert_eq!(tree.as_array().unwrap().len(), 1);
+        let gone = tree[0].as_object().unwrap();
+        assert_eq!(gone.len(), 3);
+        assert_eq!(gone["username"], "gone");
+        assert_eq!(gone["deleted"], true);
+        assert_eq!(gone["referred"][0]["username"], "child");
+    }
+
+    #[test]
+    fn hidden_tree_drops_deactivated_branches() {
+        let users = vec![
+            user("gone", Some("root"), true),
+            user("active", Some("root"), false),
+            user("child", Some("gone"), false),
+        ];
+
+        let (tree, _) = tree(users, shape(DeactivatedReferrer::Hide));
+        assert_eq!(tree.as_array().unwrap().len(), 1);
+        assert_eq!(tree[0]["username"], "active");
+    }
+
+    #[test]
+    fn wide_nodes_are_cut_off_with_a_count() {
+        let mut users: Vec<User> = (0..4)
+            .map(|n| user(&format!("direct{n}"), Some("root"), false))
+            .collect();
+        users.extend((0..5).map(|n| user(&format!("second{n}"), Some("direct0"), false)));
+
+        let (tree, more) = tree(
+            users,
+            TreeShape {
+                max_children: 3,
+                ..shape(DeactivatedReferrer::Flag)
+            },
+        );
+        assert_eq!(more, Some(1));
+        assert_eq!(tree.as_array().unwrap().len(), 3);
+        assert_eq!(tree[0]["referred"].as_array().unwrap().len(), 3);
+        assert_eq!(tree[0]["more"], 2);
+        assert!(tree[1].get("more").is_none());
+    }
+
+    #[test]
+    fn deep_branches_are_marked_truncated() {
+        let mut deep = user("second", Some("first"), false);
+        deep.referrals = 1;
+        let users = vec![
+            user("first", Some("root"), false),
+            deep,
+            user("sibling", Some("first"), false),
+        ];
+
+        let (tree, more) = tree(
+            users,
+            TreeShape {
+                max_depth: 2,
+                ..shape(DeactivatedReferrer::Flag)
+            },
+        );
+        assert_eq!(more, None);
+        assert!(tree[0].get("truncated").is_none());
+        let second = &tree[0]["referred"][0];
+        assert_eq!(second["username"], "second");
+        assert_eq!(second["truncated"], true);
+        assert!(tree[0]["referred"][1].get("truncated").is_none());
+    }
+
+    #[test]
+    fn share_links_encode_the_invite_code() {
+        let base_url = Url::parse("https://app.example").unwrap();
+        assert_eq!(
+            share_link(&base_url, "ALICE1234").as_str(),
+            "https://app.example/?ref=ALICE1234"
+        );
+        assert_eq!(
+            share_link(&base_url, "a b&c=d#e").as_str(),
+            "https://app.example/?ref=a+b%26c%3Dd%23e"
+        );
+    }
+
+    #[test]
+    fn share_links_keep_the_base_path_and_query() {
+        let base_url = Url::parse("https://app.example/invite?src=qr").unwrap();
+        assert_eq!(
+            share_link(&base_url, "ALICE1234").as_str(),
+            "https://app.example/invite?src=qr&ref=ALICE1234"
+        );
+    }
+
+    #[sqlx::test]
+    async fn deleting_an_account_revokes_every_token(pool: PgPool) {
+        let app = TestApp::new(test_config(), pool);
+        let tokens = app.authenticate("alice", None).await;
+        let token = tokens["token"].as_str().unwrap();
+
+        let (status, _) = app
+            .call(Method::DELETE, "/users/me", Some(token), None)
+            .await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+
+        let (status, _) = app.call(Method::GET, "/users/me", Some(token), None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = app
+            .call(
+                Method::POST,
+                "/token/refresh",
+                None,
+                Some(json!({ "refreshToken": tokens["refreshToken"] })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[sqlx::test]
+    async fn deleting_an_account_hands_its_referrals_up(pool: PgPool) {
+        let app = TestApp::new(test_config(), pool.clone());
+        app.authenticate("carol", None).await;
+        let carol = get_user_by_username(&pool, &"carol".to_owned().into())
+            .await
+            .unwrap()
+            .unwrap();
+        let bob = app
+            .authenticate("bob", Some(&carol.invite_code.inner()))
+            .await;
+        let bob_code = get_user_by_username(&pool, &"bob".to_owned().into())
+            .await
+            .unwrap()
+            .unwrap()
+            .invite_code;
+        app.authenticate("dave", Some(&bob_code.inner())).await;
+
+        let (status, _) = app
+            .call(Method::DELETE, "/users/me", bob["token"].as_str(), None)
+            .await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+
+        let dave = get_user_by_username(&pool, &"dave".to_owned().into())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(dave.referred_by, Some(carol.username));
+    }
+
+    #[sqlx::test]
+    async fn deactivating_an_account_revokes_every_token(pool: PgPool) {
+        let app = TestApp::new(test_config(), pool);
+        let first = app.authenticate("alice", None).await;
+        let second = app.authenticate("alice", None).await;
+
+        let (status, _) = app
+            .call(
+                Method::POST,
+                "/users/me/deactivate",
+                first["token"].as_str(),
+                None,
+            )
+            .await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+
+        for tokens in [&first, &second] {
+            let (status, _) = app
+                .call(Method::GET, "/users/me", tokens["token"].as_str(), None)
+                .await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+            let (status, _) = app
+                .call(
+                    Method::POST,
+                    "/token/refresh",
+                    None,
+                    Some(json!({ "refreshToken": tokens["refreshToken"] })),
+                )
+                .await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+        }
+    }
+
+    /// Registers `username` and returns their access token.
+    async fn token(app: &TestApp, username: &str) -> String {
+        app.authenticate(username, None).await["token"]
+            .as_str()
+            .unwrap()
+            .to_owned()
+    }
+
+    async fn invite_code(pool: &PgPool, username: &str) -> String {
+        get_user_by_username(pool, &username.to_owned().into())
+            .await
+            .unwrap()
+            .unwrap()
+            .invite_code
+            .inner()
+    }
+
+    #[sqlx::test]
+    async fn batches_are_capped_at_a_hundred_usernames(pool: PgPool) {
+        let app = TestApp::new(test_config(), pool);
+        let token = token(&app, "alice").await;
+        let usernames =
+            |count: usize| -> Vec<String> { (0..count).map(|n| format!("user{n}")).collect() };
+
+        let (status, body) = app
+            .call(
+                Method::POST,
+                "/users/batch",
+                Some(&token),
+                Some(json!({ "usernames": usernames(100) })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["notFound"].as_array().unwrap().len(), 100);
+
+        let (status, body) = app
+            .call(
+                Method::POST,
+                "/users/batch",
+                Some(&token),
+                Some(json!({ "usernames": usernames(101) })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["code"], "BATCH_TOO_LARGE");
+    }
+
+    #[sqlx::test]
+    async fn listing_without_a_total_still_tells_if_there_is_more(pool: PgPool) {
+        let app = TestApp::new(test_config(), pool);
+        for username in ["bob", "carol"] {
+            app.authenticate(username, None).await;
+        }
+        let token = token(&app, "alice").await;
+
+        let (status, body) = app
+            .call(
+                Method::GET,
+                "/users?limit=1&with_total=false",
+                Some(&token),
+                None,
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["users"].as_array().unwrap().len(), 1);
+        assert_eq!(body["hasNext"], true);
+        assert!(body.get("totalItems").is_none());
+        assert!(body.get("totalPages").is_none());
+
+        let (_, body) = app
+            .call(Method::GET, "/users?limit=1", Some(&token), None)
+            .await;
+        assert_eq!(body["totalItems"], 2);
+        assert_eq!(body["totalPages"], 2);
+    }
+
+    #[sqlx::test]
+    async fn cursors_page_through_users_signing_up_mid_paging(pool: PgPool) {
+        let app = TestApp::new(test_config(), pool);
+        for username in ["bob", "carol", "dave", "erin"] {
+            app.authenticate(username, None).await;
+        }
+        let token = token(&app, "alice").await;
+
+        let (status, body) = app
+            .call(Method::GET, "/users?limit=2", Some(&token), None)
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        let mut seen: Vec<String> = vec![];
+        let mut page = body;
+        // a newcomer would push the second page along by one with offsets
+        app.authenticate("frank", None).await;
+        loop {
+            for user in page["users"].as_array().unwrap() {
+                seen.push(user["username"].as_str().unwrap().to_owned());
+            }
+            let Some(cursor) = page["nextCursor"].as_str() else {
+                break;
+            };
+            let (status, body) = app
+                .call(
+                    Method::GET,
+                    &format!("/users?limit=2&cursor={cursor}"),
+                    Some(&token),
+                    None,
+                )
+                .await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(body["hasPrev"], true);
+            page = body;
+        }
+        assert_eq!(seen, ["erin", "dave", "carol", "bob"]);
+    }
+
+    #[sqlx::test]
+    async fn cursors_are_only_accepted_by_join_date(pool: PgPool) {
+        let app = TestApp::new(test_config(), pool);
+        for username in ["bob", "carol"] {
+            app.authenticate(username, None).await;
+        }
+        let token = token(&app, "alice").await;
+        let (_, body) = app
+            .call(Method::GET, "/users?limit=1", Some(&token), None)
+            .await;
+        let cursor = body["nextCursor"].as_str().unwrap();
+
+        for uri in [
+            format!("/users?cursor={cursor}&sort=referrals"),
+            "/users?cursor=garbage".to_owned(),
+        ] {
+            let (status, body) = app.call(Method::GET, &uri, Some(&token), None).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{uri}");
+            assert_eq!(body["code"], "INVALID_CURSOR");
+        }
+
+        // sorting by referrals pages by offset and hands out no cursor
+        let (_, body) = app
+            .call(
+                Method::GET,
+                "/users?limit=1&sort=referrals",
+                Some(&token),
+                None,
+            )
+            .await;
+        assert_eq!(body["hasNext"], true);
+        assert!(body.get("nextCursor").is_none());
+    }
+
+    #[sqlx::test]
+    async fn listings_are_read_from_the_replica(pool: PgPool) {
+        // a "replica" that only knows about ghost, next to the real tables
+        for statement in [
+            "create schema replica",
+            "create table replica.users (like public.users including all)",
+            "insert into replica.users (uid, username, invite_code) values (gen_random_uuid(), 'ghost', 'gho123456')",
+        ] {
+            sqlx::query(statement).execute(&pool).await.unwrap();
+        }
+        let replica = sqlx::postgres::PgPoolOptions::new()
+            .connect_with(
+                pool.connect_options()
+                    .clone()
+                    .options([("search_path", "replica")]),
+            )
+            .await
+            .unwrap();
+        let app = TestApp::with_read_pool(test_config(), pool, Some(replica));
+        app.authenticate("bob", None).await;
+        let token = token(&app, "alice").await;
+
+        // the caller is still looked up on the primary
+        let (status, body) = app.call(Method::GET, "/users/me", Some(&token), None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["username"], "alice");
+
+        let (status, body) = app.call(Method::GET, "/users", Some(&token), None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["users"].as_array().unwrap().len(), 1);
+        assert_eq!(body["users"][0]["username"], "ghost");
+    }
+
+    #[sqlx::test]
+    async fn listings_filter_by_invite_code_and_referrer(pool: PgPool) {
+        let app = TestApp::new(test_config(), pool.clone());
+        app.authenticate("bob", None).await;
+        let bob = get_user_by_username(&pool, &"bob".to_owned().into())
+            .await
+            .unwrap()
+            .unwrap();
+        app.authenticate("carol", Some(&bob.invite_code.inner()))
+            .await;
+        let token = token(&app, "alice").await;
+        let list = |uri: String| {
+            let (app, token) = (&app, &token);
+            async move {
+                let (status, body) = app.call(Method::GET, &uri, Some(token), None).await;
+                assert_eq!(status, StatusCode::OK, "{body}");
+                body["users"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|u| u["username"].as_str().unwrap().to_owned())
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        let code = bob.invite_code.inner().to_lowercase();
+        assert_eq!(list(format!("/users?invite_code={code}")).await, ["bob"]);
+        assert_eq!(
+            list("/users?referred_by=%20BOB%20".to_owned()).await,
+            ["carol"]
+        );
+
+        let (status, body) = app
+            .call(
+                Method::GET,
+                &format!("/users?invite_code={}", "x".repeat(100)),
+                Some(&token),
+                None,
+            )
+            .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["code"], "INVALID_INVITE_CODE");
+    }
+
+    #[sqlx::test]
+    async fn referrals_list_active_direct_referrals_newest_first(pool: PgPool) {
+        let app = TestApp::new(test_config(), pool.clone());
+        let alice = token(&app, "alice").await;
+        let code = invite_code(&pool, "alice").await;
+        for username in ["bob", "carol"] {
+            app.authenticate(username, Some(&code)).await;
+        }
+        let dave = app.authenticate("dave", Some(&code)).await;
+        app.authenticate("erin", Some(&invite_code(&pool, "bob").await))
+            .await;
+        let (status, _) = app
+            .call(
+                Method::POST,
+                "/users/me/deactivate",
+                dave["token"].as_str(),
+                None,
+            )
+            .await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+
+        let mut listed = vec![];
+        for page in [1, 2] {
+            let (status, body) = app
+                .call(
+                    Method::GET,
+                    &format!("/users/me/referrals?limit=1&page={page}"),
+                    Some(&alice),
+                    None,
+                )
+                .await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(body["totalItems"], 2);
+            assert_eq!(body["hasNext"], page == 1);
+            listed.push(body["users"][0]["username"].as_str().unwrap().to_owned());
+        }
+        assert_eq!(listed, ["carol", "bob"]);
+
+        let bob = app.authenticate("bob", None).await;
+        let (_, body) = app
+            .call(
+                Method::GET,
+                "/users/me/referrals",
+                bob["token"].as_str(),
+                None,
+            )
+            .await;
+        assert_eq!(body["users"][0]["username"], "erin");
+        assert_eq!(body["users"][0]["referrals"], 0);
+        assert_eq!(body["totalItems"], 1);
+    }
+
+    #[sqlx::test]
+    async fn login_history_lists_the_callers_logins(pool: PgPool) {
+        let app = TestApp::new(test_config(), pool);
+        app.authenticate("bob", None).await;
+        for _ in 0..2 {
+            app.authenticate("alice", None).await;
+        }
+        let token = token(&app, "alice").await;
+
+        let (status, body) = app
+            .call(Method::GET, "/users/me/logins?limit=2", Some(&token), None)
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["totalItems"], 3);
+        assert_eq!(body["hasNext"], true);
+        let logins = body["logins"].as_array().unwrap();
+        assert_eq!(logins.len(), 2);
+        assert_eq!(logins[0]["ipAddress"], "127.0.0.1");
+        let logged_in_at = |login: &serde_json::Value| {
+            OffsetDateTime::parse(
+                login["loggedInAt"].as_str().unwrap(),
+                &time::format_description::well_known::Rfc3339,
+            )
+            .unwrap()
+        };
+        assert!(logged_in_at(&logins[0]) >= logged_in_at(&logins[1]));
+    }
+}
@@ -0,0 +1,90 @@
+use std::net::{IpAddr, SocketAddr};
+
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts},
+    http::request::Parts,
+};
+
+use crate::{config::Config, domain::errors::ApiError};
+
+/// The caller's IP. `X-Forwarded-For` is only honoured when the app is
+/// configured to sit behind a trusted proxy, otherwise the peer address is used.
+pub struct ClientIp(pub IpAddr);
+
+/// The client address in an `X-Forwarded-For` value behind `trusted_hops`
+/// proxies. Each proxy appends the address it received the request from, so
+/// the client is `trusted_hops` entries from the right. Anything further
+/// left was sent by the client and can't be trusted.
+fn forwarded_client(forwarded_for: &str, trusted_hops: usize) -> Option<IpAddr> {
+    let hops: Vec<&str> = forwarded_for.split(',').collect();
+    let index = hops.len().checked_sub(trusted_hops.max(1))?;
+    hops[index].trim().parse().ok()
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for ClientIp
+where
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let trusted_hops = parts
+            .extensions
+            .get::<Config>()
+            .filter(|c| c.application.trust_forwarded_for)
+            .map(|c| c.application.trusted_proxy_hops);
+
+        if let Some(trusted_hops) = trusted_hops {
+            let forwarded = parts
+                .headers
+                .get("x-forwarded-for")
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| forwarded_client(v, trusted_hops));
+
+            if let Some(ip) = forwarded {
+                return Ok(Self(ip));
+            }
+        }
+
+        let ConnectInfo(addr) = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .ok_or_else(|| {
+                tracing::error!("client ip extraction failed >>> missing connect info");
+                ApiError::ServerError
+            })?;
+
+        Ok(Self(addr.ip()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn takes_the_address_the_trusted_proxy_appended() {
+        let forwarded = "6.6.6.6, 203.0.113.7";
+        assert_eq!(
+            forwarded_client(forwarded, 1),
+            Some("203.0.113.7".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn skips_one_entry_per_trusted_hop() {
+        let forwarded = "6.6.6.6, 203.0.113.7, 10.0.0.2";
+        assert_eq!(
+            forwarded_client(forwarded, 2),
+            Some("203.0.113.7".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn ignores_headers_shorter_than_the_proxy_chain() {
+        assert_eq!(forwarded_client("203.0.113.7", 2), None);
+        assert_eq!(forwarded_client("not-an-ip", 1), None);
+    }
+}
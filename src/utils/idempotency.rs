@@ -0,0 +1,152 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// SHA-256 of the request payload. Stable across restarts and builds, unlike
+/// `DefaultHasher`, so it stays valid for a store shared between instances.
+pub type Fingerprint = [u8; 32];
+
+/// A response kept so a retried request can be answered without running it
+/// again.
+#[derive(Clone)]
+pub struct CachedResponse {
+    pub body: String,
+}
+
+pub enum IdempotencyStatus {
+    /// First time this key was seen, the caller now owns it and must
+    /// `complete` or `abandon` it.
+    New,
+    /// Another request with this key hasn't finished yet.
+    InProgress,
+    Completed(CachedResponse),
+    /// The key was already used for a different request.
+    Mismatch,
+}
+
+/// Remembers requests by `Idempotency-Key` so retries get the original
+/// response. Entries are matched against a fingerprint of the request payload.
+pub trait IdempotencyStore: Send + Sync {
+    /// Claims `key` for a request with `fingerprint` for `ttl`, or reports what
+    /// is already stored under it.
+    fn begin(&self, key: &str, fingerprint: Fingerprint, ttl: Duration) -> IdempotencyStatus;
+
+    fn complete(&self, key: &str, response: CachedResponse);
+
+    /// Releases a claimed key without storing a response, so a retry runs the
+    /// request again.
+    fn abandon(&self, key: &str);
+}
+
+struct Entry {
+    fingerprint: Fingerprint,
+    expires_at: Instant,
+    response: Option<CachedResponse>,
+}
+
+#[derive(Default)]
+pub struct InMemoryIdempotencyStore {
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+impl IdempotencyStore for InMemoryIdempotencyStore {
+    fn begin(&self, key: &str, fingerprint: Fingerprint, ttl: Duration) -> IdempotencyStatus {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, e| e.expires_at > now);
+
+        match entries.get(key) {
+            Some(entry) if entry.fingerprint != fingerprint => IdempotencyStatus::Mismatch,
+            Some(Entry {
+                response: Some(response),
+                ..
+            }) => IdempotencyStatus::Completed(response.clone()),
+            Some(_) => IdempotencyStatus::InProgress,
+            None => {
+                entries.insert(
+                    key.to_owned(),
+                    Entry {
+                        fingerprint,
+                        expires_at: now + ttl,
+                        response: None,
+                    },
+                );
+                IdempotencyStatus::New
+            }
+        }
+    }
+
+    fn complete(&self, key: &str, response: CachedResponse) {
+        if let Some(entry) = self.entries.lock().unwrap().get_mut(key) {
+            entry.response = Some(response);
+        }
+    }
+
+    fn abandon(&self, key: &str) {
+        self.entries.lock().unwrap().remove(key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TTL: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn replays_the_completed_response_for_the_same_payload() {
+        let store = InMemoryIdempotencyStore::default();
+        assert!(matches!(
+            store.begin("key", [1; 32], TTL),
+            IdempotencyStatus::New
+        ));
+        assert!(matches!(
+            store.begin("key", [1; 32], TTL),
+            IdempotencyStatus::InProgress
+        ));
+
+        store.complete(
+            "key",
+            CachedResponse {
+                body: "{}".to_owned(),
+            },
+        );
+        match store.begin("key", [1; 32], TTL) {
+            IdempotencyStatus::Completed(response) => assert_eq!(response.body, "{}"),
+            _ => panic!("expected the cached response"),
+        }
+    }
+
+    #[test]
+    fn rejects_a_different_payload_under_the_same_key() {
+        let store = InMemoryIdempotencyStore::default();
+        store.begin("key", [1; 32], TTL);
+        assert!(matches!(
+            store.begin("key", [2; 32], TTL),
+            IdempotencyStatus::Mismatch
+        ));
+        assert!(matches!(
+            store.begin("other", [2; 32], TTL),
+            IdempotencyStatus::New
+        ));
+    }
+
+    #[test]
+    fn abandoned_and_expired_keys_can_be_reused() {
+        let store = InMemoryIdempotencyStore::default();
+        store.begin("key", [1; 32], TTL);
+        store.abandon("key");
+        assert!(matches!(
+            store.begin("key", [2; 32], TTL),
+            IdempotencyStatus::New
+        ));
+
+        store.begin("expiring", [1; 32], Duration::ZERO);
+        assert!(matches!(
+            store.begin("expiring", [2; 32], TTL),
+            IdempotencyStatus::New
+        ));
+    }
+}
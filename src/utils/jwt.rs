@@ -1,33 +1,176 @@
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
-use secrecy::ExposeSecret;
+use anyhow::Context;
+use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use secrecy::{ExposeSecret, Secret};
+use uuid::Uuid;
 
 use crate::{
     config::JwtConfig,
     domain::{
         errors::JWTError,
-        fields::{Claims, Username},
+        fields::{Claims, TokenType, Username},
     },
 };
 
+struct KeyPair {
+    algorithm: Algorithm,
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+    validation: Validation,
+}
+
+impl KeyPair {
+    fn from_secret(secret: &Secret<String>, algorithm: Algorithm) -> Self {
+        let secret = secret.expose_secret().as_bytes();
+        Self {
+            algorithm,
+            encoding: EncodingKey::from_secret(secret),
+            decoding: DecodingKey::from_secret(secret),
+            validation: Validation::new(algorithm),
+        }
+    }
+
+    fn from_pem_files(
+        algorithm: Algorithm,
+        private_key_path: &Path,
+        public_key_path: &Path,
+    ) -> anyhow::Result<Self> {
+        let private_pem = std::fs::read(private_key_path)
+            .with_context(|| format!("reading {}", private_key_path.display()))?;
+        let public_pem = std::fs::read(public_key_path)
+            .with_context(|| format!("reading {}", public_key_path.display()))?;
+
+        let (encoding, decoding) = match algorithm {
+            Algorithm::RS256
+            | Algorithm::RS384
+            | Algorithm::RS512
+            | Algorithm::PS256
+            | Algorithm::PS384
+            | Algorithm::PS512 => (
+                EncodingKey::from_rsa_pem(&private_pem)?,
+                DecodingKey::from_rsa_pem(&public_pem)?,
+            ),
+            Algorithm::ES256 | Algorithm::ES384 => (
+                EncodingKey::from_ec_pem(&private_pem)?,
+                DecodingKey::from_ec_pem(&public_pem)?,
+            ),
+            Algorithm::EdDSA => (
+                EncodingKey::from_ed_pem(&private_pem)?,
+                DecodingKey::from_ed_pem(&public_pem)?,
+            ),
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
+                anyhow::bail!("{:?} signs with jwt.secret, not key files", algorithm)
+            }
+        };
+
+        Ok(Self {
+            algorithm,
+            encoding,
+            decoding,
+            validation: Validation::new(algorithm),
+        })
+    }
+
+    /// Only accepts tokens issued by `jwt.iss`, allowing `jwt.leeway_secs` of
+    /// clock skew on `exp`.
+    fn validate_with(mut self, jwt_config: &JwtConfig) -> Self {
+        self.validation.leeway = jwt_config.leeway_secs;
+        self.validation.set_issuer(&[&jwt_config.iss]);
+        self
+    }
+}
+
+/// Signing and verification keys, loaded once at startup. Access tokens use
+/// `jwt.algorithm`, which can be asymmetric so other services can verify them
+/// with just the public key. Refresh tokens are only ever read back by this
+/// service and stay on HS256 with `jwt.refresh_secret`.
+pub struct JwtKeys {
+    access: KeyPair,
+    refresh: KeyPair,
+}
+
+impl JwtKeys {
+    pub fn from_config(jwt_config: &JwtConfig) -> anyhow::Result<Self> {
+        let access = match (&jwt_config.private_key_path, &jwt_config.public_key_path) {
+            (Some(private_key_path), Some(public_key_path)) => {
+                KeyPair::from_pem_files(jwt_config.algorithm, private_key_path, public_key_path)?
+            }
+            _ => KeyPair::from_secret(&jwt_config.secret, jwt_config.algorithm),
+        };
+
+        Ok(Self {
+            access: access.validate_with(jwt_config),
+            refresh: KeyPair::from_secret(&jwt_config.refresh_secret, Algorithm::HS256)
+                .validate_with(jwt_config),
+        })
+    }
+}
+
 pub fn generate_auth_token(
     username: &Username,
+    session_id: &str,
+    jwt_config: &JwtConfig,
+    keys: &JwtKeys,
+) -> Result<String, JWTError> {
+    generate_token(
+        username,
+        session_id,
+        TokenType::Access,
+        &keys.access,
+        jwt_config.exp,
+        jwt_config,
+    )
+}
+
+pub fn generate_refresh_token(
+    username: &Username,
+    session_id: &str,
+    jwt_config: &JwtConfig,
+    keys: &JwtKeys,
+) -> Result<String, JWTError> {
+    generate_token(
+        username,
+        session_id,
+        TokenType::Refresh,
+        &keys.refresh,
+        jwt_config.refresh_exp,
+        jwt_config,
+    )
+}
+
+pub fn decode_auth_token(token: &str, keys: &JwtKeys) -> Result<Claims, JWTError> {
+    decode_token(token, TokenType::Access, &keys.access)
+}
+
+pub fn decode_refresh_token(token: &str, keys: &JwtKeys) -> Result<Claims, JWTError> {
+    decode_token(token, TokenType::Refresh, &keys.refresh)
+}
+
+fn generate_token(
+    username: &Username,
+    session_id: &str,
+    token_type: TokenType,
+    keys: &KeyPair,
+    exp_secs: u64,
     jwt_config: &JwtConfig,
 ) -> Result<String, JWTError> {
-    let exp = SystemTime::now() + Duration::from_secs(jwt_config.exp);
+    let now = SystemTime::now();
+    let exp = now + Duration::from_secs(exp_secs);
     let claims = Claims {
+        jti: Uuid::new_v4().to_string(),
         iss: jwt_config.iss.clone(),
         sub: username.inner(),
+        iat: now.duration_since(UNIX_EPOCH).unwrap().as_secs() as usize,
         exp: exp.duration_since(UNIX_EPOCH).unwrap().as_secs() as usize,
+        token_type,
+        sid: session_id.to_owned(),
     };
 
-    let token = encode(
-        &Header::default(),
-        &claims,
-        &EncodingKey::from_secret(jwt_config.secret.expose_secret().as_ref()),
-    )
-    .map_err(|e| {
+    let token = encode(&Header::new(keys.algorithm), &claims, &keys.encoding).map_err(|e| {
         tracing::error!("auth token generation failed >>> {}", e);
         JWTError::GenerationFailed(e.into_kind())
     })?;
@@ -35,16 +178,154 @@ pub fn generate_auth_token(
     Ok(token)
 }
 
-pub fn decode_auth_token(token: &str, jwt_config: &JwtConfig) -> Result<Claims, JWTError> {
-    let token_data = decode::<Claims>(
-        &token,
-        &DecodingKey::from_secret(jwt_config.secret.expose_secret().as_ref()),
-        &Validation::default(),
-    )
-    .map_err(|e| {
+fn decode_token(token: &str, token_type: TokenType, keys: &KeyPair) -> Result<Claims, JWTError> {
+    let token_data = decode::<Claims>(token, &keys.decoding, &keys.validation).map_err(|e| {
         tracing::error!("auth token decode failed >>> {}", e);
         JWTError::DecodeFailed(e.into_kind())
     })?;
 
+    if token_data.claims.token_type != token_type {
+        tracing::error!(
+            "auth token decode failed >>> expected {:?} token, got {:?}",
+            token_type,
+            token_data.claims.token_type
+        );
+        return Err(JWTError::WrongTokenType);
+    }
+
     Ok(token_data.claims)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config;
+
+    #[test]
+    fn access_and_refresh_tokens_round_trip() {
+        let config = test_config().jwt;
+        let keys = JwtKeys::from_config(&config).unwrap();
+        let username = Username::from("alice".to_owned());
+
+        let access = generate_auth_token(&username, "session", &config, &keys).unwrap();
+        let claims = decode_auth_token(&access, &keys).unwrap();
+        assert_eq!(claims.sub, "alice");
+        assert_eq!(claims.sid, "session");
+        assert_eq!(claims.token_type, TokenType::Access);
+
+        let refresh = generate_refresh_token(&username, "session", &config, &keys).unwrap();
+        let claims = decode_refresh_token(&refresh, &keys).unwrap();
+        assert_eq!(claims.sid, "session");
+        assert_eq!(claims.token_type, TokenType::Refresh);
+    }
+
+    #[test]
+    fn token_types_cant_be_swapped() {
+        let config = test_config().jwt;
+        let keys = JwtKeys::from_config(&config).unwrap();
+        let username = Username::from("alice".to_owned());
+
+        // signed with different secrets, so a swapped token fails to verify
+        // before its type is even checked
+        let access = generate_auth_token(&username, "session", &config, &keys).unwrap();
+        assert!(decode_refresh_token(&access, &keys).is_err());
+
+        let refresh = generate_refresh_token(&username, "session", &config, &keys).unwrap();
+        assert!(decode_auth_token(&refresh, &keys).is_err());
+    }
+
+    fn rs256_config() -> JwtConfig {
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/jwt");
+        let mut config = test_config().jwt;
+        config.algorithm = Algorithm::RS256;
+        config.private_key_path = Some(fixtures.join("rs256_private.pem"));
+        config.public_key_path = Some(fixtures.join("rs256_public.pem"));
+        config
+    }
+
+    #[test]
+    fn rs256_access_tokens_round_trip() {
+        let config = rs256_config();
+        let keys = JwtKeys::from_config(&config).unwrap();
+        let username = Username::from("alice".to_owned());
+
+        let access = generate_auth_token(&username, "session", &config, &keys).unwrap();
+        assert_eq!(
+            jsonwebtoken::decode_header(&access).unwrap().alg,
+            Algorithm::RS256
+        );
+        assert_eq!(decode_auth_token(&access, &keys).unwrap().sub, "alice");
+
+        // refresh tokens stay on HS256 whatever the access algorithm
+        let refresh = generate_refresh_token(&username, "session", &config, &keys).unwrap();
+        assert_eq!(
+            jsonwebtoken::decode_header(&refresh).unwrap().alg,
+            Algorithm::HS256
+        );
+        assert!(decode_refresh_token(&refresh, &keys).is_ok());
+    }
+
+    #[test]
+    fn rs256_keys_reject_hmac_signed_tokens() {
+        let hmac_config = test_config().jwt;
+        let hmac_keys = JwtKeys::from_config(&hmac_config).unwrap();
+        let username = Username::from("alice".to_owned());
+        let forged = generate_auth_token(&username, "session", &hmac_config, &hmac_keys).unwrap();
+
+        let keys = JwtKeys::from_config(&rs256_config()).unwrap();
+        assert!(decode_auth_token(&forged, &keys).is_err());
+    }
+
+    fn sign_access_claims(config: &JwtConfig, iss: &str, exp_offset_secs: i64) -> String {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        let claims = Claims {
+            jti: Uuid::new_v4().to_string(),
+            iss: iss.to_owned(),
+            sub: "alice".to_owned(),
+            iat: now as usize,
+            exp: (now + exp_offset_secs) as usize,
+            token_type: TokenType::Access,
+            sid: "session".to_owned(),
+        };
+        let key = EncodingKey::from_secret(config.secret.expose_secret().as_bytes());
+        encode(&Header::new(config.algorithm), &claims, &key).unwrap()
+    }
+
+    #[test]
+    fn tokens_from_another_issuer_are_rejected() {
+        let config = test_config().jwt;
+        let keys = JwtKeys::from_config(&config).unwrap();
+
+        let token = sign_access_claims(&config, &config.iss, 60);
+        assert!(decode_auth_token(&token, &keys).is_ok());
+
+        let token = sign_access_claims(&config, "someone-else", 60);
+        assert!(matches!(
+            decode_auth_token(&token, &keys),
+            Err(JWTError::DecodeFailed(
+                jsonwebtoken::errors::ErrorKind::InvalidIssuer
+            ))
+        ));
+    }
+
+    #[test]
+    fn expiry_allows_the_configured_leeway() {
+        let mut config = test_config().jwt;
+        config.leeway_secs = 60;
+        let keys = JwtKeys::from_config(&config).unwrap();
+
+        let token = sign_access_claims(&config, &config.iss, -30);
+        assert!(decode_auth_token(&token, &keys).is_ok());
+
+        let token = sign_access_claims(&config, &config.iss, -120);
+        assert!(matches!(
+            decode_auth_token(&token, &keys),
+            Err(JWTError::DecodeFailed(
+                jsonwebtoken::errors::ErrorKind::ExpiredSignature
+            ))
+        ));
+    }
+}
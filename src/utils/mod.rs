@@ -1 +1,5 @@
-pub mod jwt;
\ No newline at end of file
+pub mod client_ip;
+pub mod idempotency;
+pub mod jwt;
+pub mod rate_limit;
+pub mod revocation;
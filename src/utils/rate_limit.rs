@@ -0,0 +1,127 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Counts hits per client IP over a sliding window.
+pub struct IpWindowCounter {
+    limit: usize,
+    window: Duration,
+    hits: Mutex<Hits>,
+}
+
+struct Hits {
+    by_ip: HashMap<IpAddr, VecDeque<Instant>>,
+    last_sweep: Instant,
+}
+
+impl IpWindowCounter {
+    pub fn new(limit: usize, window: Duration) -> Self {
+        Self {
+            limit,
+            window,
+            hits: Mutex::new(Hits {
+                by_ip: HashMap::new(),
+                last_sweep: Instant::now(),
+            }),
+        }
+    }
+
+    /// Records a hit for `ip` if it is still under the limit, otherwise returns
+    /// how long until the oldest hit in the window expires.
+    pub fn try_acquire(&self, ip: IpAddr) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut hits = self.hits.lock().unwrap();
+
+        // only `ip` is pruned per request, IPs that went quiet are dropped in
+        // a sweep at most once per window so a burst of new IPs stays O(1)
+        if now.duration_since(hits.last_sweep) >= self.window {
+            hits.by_ip.retain(|_, h| {
+                h.back()
+                    .is_some_and(|t| now.duration_since(*t) < self.window)
+            });
+            hits.last_sweep = now;
+        }
+
+        let entry = hits.by_ip.entry(ip).or_default();
+        while entry
+            .front()
+            .is_some_and(|t| now.duration_since(*t) >= self.window)
+        {
+            entry.pop_front();
+        }
+
+        if entry.len() >= self.limit {
+            let oldest = entry.front().copied().unwrap_or(now);
+            return Err(self.window.saturating_sub(now.duration_since(oldest)));
+        }
+
+        entry.push_back(now);
+        Ok(())
+    }
+
+    /// Takes back the latest hit for `ip`, for an action that was counted but
+    /// didn't go through after all.
+    pub fn release(&self, ip: IpAddr) {
+        if let Some(entry) = self.hits.lock().unwrap().by_ip.get_mut(&ip) {
+            entry.pop_back();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALICE: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 1));
+    const BOB: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 2));
+
+    #[test]
+    fn rejects_hits_over_the_limit_per_ip() {
+        let counter = IpWindowCounter::new(2, Duration::from_secs(60));
+        assert!(counter.try_acquire(ALICE).is_ok());
+        assert!(counter.try_acquire(ALICE).is_ok());
+
+        let retry_after = counter.try_acquire(ALICE).unwrap_err();
+        assert!(retry_after <= Duration::from_secs(60));
+        assert!(counter.try_acquire(BOB).is_ok());
+    }
+
+    #[test]
+    fn allows_hits_again_once_the_window_passes() {
+        let counter = IpWindowCounter::new(1, Duration::from_millis(20));
+        assert!(counter.try_acquire(ALICE).is_ok());
+        assert!(counter.try_acquire(ALICE).is_err());
+
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(counter.try_acquire(ALICE).is_ok());
+    }
+
+    #[test]
+    fn sweeps_ips_that_went_quiet() {
+        let counter = IpWindowCounter::new(1, Duration::from_millis(20));
+        assert!(counter.try_acquire(ALICE).is_ok());
+
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(counter.try_acquire(BOB).is_ok());
+
+        let hits = counter.hits.lock().unwrap();
+        assert!(!hits.by_ip.contains_key(&ALICE));
+        assert!(hits.by_ip.contains_key(&BOB));
+    }
+
+    #[test]
+    fn released_hits_no_longer_count() {
+        let counter = IpWindowCounter::new(1, Duration::from_secs(60));
+        assert!(counter.try_acquire(ALICE).is_ok());
+        counter.release(ALICE);
+        assert!(counter.try_acquire(ALICE).is_ok());
+        assert!(counter.try_acquire(ALICE).is_err());
+
+        // releasing an ip that was never counted is a no-op
+        counter.release(BOB);
+        assert!(counter.try_acquire(BOB).is_ok());
+    }
+}
@@ -0,0 +1,104 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Keeps track of tokens (by `jti`) and login sessions (by `sid`) that were
+/// revoked before they expired, and of users whose every token up to some
+/// point was revoked.
+pub trait RevocationStore: Send + Sync {
+    /// Revokes `jti` until `expires_at` (unix secs), after which the token is
+    /// rejected on its own and the entry can be forgotten.
+    fn revoke(&self, jti: &str, expires_at: u64);
+
+    fn is_revoked(&self, jti: &str) -> bool;
+
+    /// Revokes every token for `sub` issued at or before `issued_before`
+    /// (unix secs). The entry can be forgotten after `expires_at`, once the
+    /// longest-lived of those tokens has expired.
+    fn revoke_all(&self, sub: &str, issued_before: u64, expires_at: u64);
+
+    fn is_revoked_for(&self, sub: &str, issued_at: u64) -> bool;
+}
+
+#[derive(Default)]
+pub struct InMemoryRevocationStore {
+    revoked: Mutex<HashMap<String, u64>>,
+    /// `sub` to (issued before, expires at)
+    revoked_users: Mutex<HashMap<String, (u64, u64)>>,
+}
+
+impl InMemoryRevocationStore {
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+}
+
+impl RevocationStore for InMemoryRevocationStore {
+    fn revoke(&self, jti: &str, expires_at: u64) {
+        let now = Self::now();
+        let mut revoked = self.revoked.lock().unwrap();
+        revoked.retain(|_, exp| *exp > now);
+        revoked.insert(jti.to_owned(), expires_at);
+    }
+
+    fn is_revoked(&self, jti: &str) -> bool {
+        let revoked = self.revoked.lock().unwrap();
+        matches!(revoked.get(jti), Some(exp) if *exp > Self::now())
+    }
+
+    fn revoke_all(&self, sub: &str, issued_before: u64, expires_at: u64) {
+        let now = Self::now();
+        let mut revoked = self.revoked_users.lock().unwrap();
+        revoked.retain(|_, (_, exp)| *exp > now);
+        revoked.insert(sub.to_owned(), (issued_before, expires_at));
+    }
+
+    fn is_revoked_for(&self, sub: &str, issued_at: u64) -> bool {
+        let revoked = self.revoked_users.lock().unwrap();
+        matches!(
+            revoked.get(sub),
+            Some((before, exp)) if issued_at <= *before && *exp > Self::now()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn in_an_hour() -> u64 {
+        InMemoryRevocationStore::now() + 3600
+    }
+
+    #[test]
+    fn rejects_a_revoked_token_but_not_a_fresh_one() {
+        let store = InMemoryRevocationStore::default();
+        store.revoke("revoked-jti", in_an_hour());
+
+        assert!(store.is_revoked("revoked-jti"));
+        assert!(!store.is_revoked("fresh-jti"));
+    }
+
+    #[test]
+    fn forgets_revocations_once_the_token_expired() {
+        let store = InMemoryRevocationStore::default();
+        store.revoke("expired-jti", InMemoryRevocationStore::now() - 1);
+
+        assert!(!store.is_revoked("expired-jti"));
+    }
+
+    #[test]
+    fn revoking_a_user_only_covers_tokens_issued_until_then() {
+        let store = InMemoryRevocationStore::default();
+        store.revoke_all("alice", 100, in_an_hour());
+
+        assert!(store.is_revoked_for("alice", 100));
+        assert!(!store.is_revoked_for("alice", 101));
+        assert!(!store.is_revoked_for("bob", 100));
+    }
+}